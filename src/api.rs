@@ -1,29 +1,52 @@
-use std::iter::zip;
+use std::collections::HashMap;
 
 use actix_web::{
 	get,
 	web::{Data, Path, Query},
 	HttpResponse, Responder,
 };
+use chrono::Utc;
+use ethers::types::Chain;
 use eyre::{eyre, OptionExt, Result};
-use redis::{Client, Commands};
 use serde::{Deserialize, Serialize};
 use shared::coin::Pair;
 
 use crate::{
-	datapoint::{Datapoint, TimeType},
-	COLLECTION_INTERVAL, CURRENT_CHAIN,
+	config,
+	datapoint::{Candle, Datapoint, Trade},
+	rpc::{EndpointStats, RpcClients},
+	store::{ChainStores, PriceStore},
+	COLLECTION_INTERVAL,
 };
 
 const MAX_DATAPOINTS: u16 = 720;
+// longest range of trades read at once, in seconds
+const MAX_TRADE_RANGE: i64 = 24 * 60 * 60;
 
-fn get_prices(
+// pair called `pair_string` and the store it is kept in, on `chain` or on the first configured
+// chain if none is given
+fn get_pair<'a>(
+	stores: &'a ChainStores,
 	pair_string: String,
-	client: &Client,
-	amount: u16,
-	interval: u16,
-	at: Option<i64>,
-) -> Result<Vec<Datapoint>> {
+	chain: Option<&str>,
+) -> Result<(Pair, &'a dyn PriceStore)> {
+	let chain: Chain = match chain {
+		Some(chain) => chain
+			.parse()
+			.map_err(|_| eyre!("invalid chain {}", chain))?,
+		None => config::current().chains[0].chain,
+	};
+
+	let store = stores
+		.get(&chain)
+		.ok_or_else(|| eyre!("prices are not collected on {}", chain))?;
+	let pair = Pair::get_pair(pair_string.as_str(), Some(chain.into())).ok_or_eyre("invalid pair")?;
+
+	Ok((pair, store.as_ref()))
+}
+
+// returns how many collected datapoints fit into one interval
+fn interval_steps(interval: u16) -> Result<u16> {
 	let collection_interval_minutes = (COLLECTION_INTERVAL.std_duration().as_secs() / 60) as u16;
 	if interval < collection_interval_minutes {
 		Err(eyre!("interval smaller than collection interval"))?;
@@ -33,47 +56,167 @@ fn get_prices(
 		Err(eyre!("interval does not fit into collection interval"))?;
 	}
 
-	let pair =
-		Pair::get_pair(pair_string.as_str(), Some(CURRENT_CHAIN.into())).ok_or_eyre("invalid pair")?;
-	let mut connection = client.get_connection()?;
-
-	let collection_interval_secs = COLLECTION_INTERVAL.std_duration().as_secs() as i64;
+	Ok(interval / collection_interval_minutes)
+}
 
-	// convert timestamp into offset
-	let offset: i32 = match at {
-		Some(timestamp) => {
-			let current_timestamp: i64 =
-				connection.lindex(format!("{}:timestamps", pair.to_string()), -1)?;
-			(current_timestamp / collection_interval_secs - timestamp / collection_interval_secs) as i32
-		}
-		None => 0,
+// reads the collected series, or the series of a single price source if one is given,
+// synthetic datapoints are left out unless `synthetic` is set
+fn get_datapoints(
+	store: &dyn PriceStore,
+	pair: &Pair,
+	source: Option<&str>,
+	amount: usize,
+	at: Option<i64>,
+	synthetic: bool,
+) -> Result<Vec<Datapoint>> {
+	let datapoints = match source {
+		Some(source) => store.get_source_datapoints(pair, source, amount, at)?,
+		None => store.get_datapoints(pair, amount, at)?,
 	};
 
-	// apply corrections to amount and offset to prepare for querrying with redis
-	let offset = (offset * -1) as isize;
-	let amount = (amount as isize * (interval / collection_interval_minutes) as isize) * -1;
-
-	let amount = amount as isize;
+	Ok(
+		datapoints
+			.into_iter()
+			.filter(|datapoint| synthetic || !datapoint.provenance.is_synthetic())
+			.collect(),
+	)
+}
 
-	let timestamps: Vec<i64> = connection.lrange(
-		format!("{}:timestamps", pair.to_string()),
-		amount + offset,
-		-1 + offset,
+fn get_prices(
+	pair: &Pair,
+	store: &dyn PriceStore,
+	source: Option<&str>,
+	amount: u16,
+	interval: u16,
+	at: Option<i64>,
+	synthetic: bool,
+) -> Result<Vec<Datapoint>> {
+	let steps = interval_steps(interval)?;
+	let datapoints = get_datapoints(
+		store,
+		pair,
+		source,
+		amount as usize * steps as usize,
+		at,
+		synthetic,
 	)?;
 
-	let prices: Vec<f64> = connection.lrange(
-		format!("{}:prices", pair.to_string()),
-		amount + offset,
-		-1 + offset,
+	Ok(
+		datapoints
+			.into_iter()
+			.filter(|x| x.timestamp / 60 % interval as i64 == 0)
+			.collect(),
+	)
+}
+
+// groups items sorted oldest first by the interval their timestamp falls into
+fn into_buckets<T>(
+	items: Vec<T>,
+	interval_secs: i64,
+	timestamp: impl Fn(&T) -> i64,
+) -> Vec<(i64, Vec<T>)> {
+	items
+		.into_iter()
+		.fold(Vec::<(i64, Vec<T>)>::new(), |mut buckets, item| {
+			let bucket = timestamp(&item) - timestamp(&item).rem_euclid(interval_secs);
+			match buckets.last_mut() {
+				Some((timestamp, items)) if *timestamp == bucket => items.push(item),
+				_ => buckets.push((bucket, vec![item])),
+			}
+
+			buckets
+		})
+}
+
+fn get_candles(
+	pair: &Pair,
+	store: &dyn PriceStore,
+	source: Option<&str>,
+	amount: u16,
+	interval: u16,
+	at: Option<i64>,
+	synthetic: bool,
+) -> Result<Vec<Candle>> {
+	let steps = interval_steps(interval)? as usize;
+	// fetch an extra interval worth of datapoints so the oldest candle is not cut short
+	let datapoints = get_datapoints(
+		store,
+		pair,
+		source,
+		(amount as usize + 1) * steps,
+		at,
+		synthetic,
 	)?;
 
-	let datapoints = zip(prices, timestamps)
-		.map(|(price, timestamp)| Datapoint::new(price, TimeType::Timestamp(timestamp)))
-		.filter_map(|x| x.ok())
-		.filter(|x| x.timestamp / 60 % interval as i64 == 0)
+	let interval_secs = interval as i64 * 60;
+	let buckets = into_buckets(datapoints, interval_secs, |datapoint| datapoint.timestamp);
+
+	let mut candles: Vec<Candle> = buckets
+		.iter()
+		.filter_map(|(timestamp, datapoints)| {
+			Candle::from_datapoints(*timestamp, interval_secs, datapoints)
+		})
 		.collect();
 
-	Ok(datapoints)
+	let excess = candles.len().saturating_sub(amount as usize);
+	candles.drain(..excess);
+
+	Ok(candles)
+}
+
+// trades from `from` (a day before `to` by default) up to `to` (now by default)
+fn get_trades(
+	pair: &Pair,
+	store: &dyn PriceStore,
+	from: Option<i64>,
+	to: Option<i64>,
+) -> Result<Vec<Trade>> {
+	let to = to.unwrap_or_else(|| Utc::now().timestamp());
+	let from = from.unwrap_or(to - MAX_TRADE_RANGE);
+
+	if from > to {
+		Err(eyre!("from is after to"))?;
+	}
+
+	if to - from > MAX_TRADE_RANGE {
+		Err(eyre!("at most a day of trades can be read at once"))?;
+	}
+
+	store.get_trades(pair, from, to + 1)
+}
+
+// newest `amount` candles built from the trades up to `at` (or now), as many as fit in a day by
+// default
+fn get_trade_candles(
+	pair: &Pair,
+	store: &dyn PriceStore,
+	amount: Option<u16>,
+	interval: u16,
+	at: Option<i64>,
+) -> Result<Vec<Candle>> {
+	if interval == 0 {
+		Err(eyre!("interval has to be at least a minute"))?;
+	}
+
+	let interval_secs = interval as i64 * 60;
+	let amount =
+		amount.unwrap_or((MAX_TRADE_RANGE / interval_secs).clamp(1, MAX_DATAPOINTS as i64) as u16);
+	if amount as i64 * interval_secs > MAX_TRADE_RANGE {
+		Err(eyre!(
+			"at most a day of trades can be read at once, lower amount or interval"
+		))?;
+	}
+
+	let at = at.unwrap_or_else(|| Utc::now().timestamp());
+	let from = at - at.rem_euclid(interval_secs) - (amount as i64 - 1) * interval_secs;
+	let window = store.get_trades(pair, from, at + 1)?;
+
+	Ok(
+		into_buckets(window, interval_secs, |trade| trade.timestamp)
+			.iter()
+			.filter_map(|(timestamp, bucket)| Candle::from_trades(*timestamp, bucket))
+			.collect(),
+	)
 }
 
 #[derive(Deserialize, Serialize)]
@@ -86,27 +229,39 @@ struct PricesQueryInfo {
 	interval: u16,
 	amount: Option<u16>,
 	at: Option<i64>,
+	source: Option<String>,
+	/// whether interpolated datapoints are returned, defaults to true
+	synthetic: Option<bool>,
+	/// defaults to the first configured chain
+	chain: Option<String>,
 }
 
 #[get("/prices/{pair}")]
 async fn prices_wrapper(
 	pair: Path<String>,
 	interval: Query<PricesQueryInfo>,
-	client: Data<Client>,
+	stores: Data<ChainStores>,
 ) -> impl Responder {
 	let pair = pair.into_inner();
 	let PricesQueryInfo {
 		interval,
 		amount,
 		at,
+		source,
+		synthetic,
+		chain,
 	} = interval.into_inner();
-	match get_prices(
-		pair,
-		client.as_ref(),
-		u16::min(MAX_DATAPOINTS, amount.unwrap_or(u16::MAX)),
-		interval,
-		at,
-	) {
+	match get_pair(&stores, pair, chain.as_deref()).and_then(|(pair, store)| {
+		get_prices(
+			&pair,
+			store,
+			source.as_deref(),
+			u16::min(MAX_DATAPOINTS, amount.unwrap_or(u16::MAX)),
+			interval,
+			at,
+			synthetic.unwrap_or(true),
+		)
+	}) {
 		Ok(mut prices) => {
 			prices.reverse();
 			HttpResponse::Ok().json(prices)
@@ -120,26 +275,31 @@ async fn prices_wrapper(
 #[derive(Deserialize)]
 struct CurrentQueryInfo {
 	at: Option<i64>,
+	chain: Option<String>,
 }
 
 #[get("/current/{pair}")]
 async fn current(
 	pair: Path<String>,
 	interval: Query<CurrentQueryInfo>,
-	client: Data<Client>,
+	stores: Data<ChainStores>,
 ) -> impl Responder {
 	let pair = pair.into_inner();
-	let CurrentQueryInfo { at } = interval.into_inner();
-	match get_prices(
-		pair,
-		client.as_ref(),
-		1,
-		(COLLECTION_INTERVAL.std_duration().as_secs() / 60) as u16,
-		match at {
-			Some(timestamp) => Some(timestamp),
-			None => None,
-		},
-	) {
+	let CurrentQueryInfo { at, chain } = interval.into_inner();
+	match get_pair(&stores, pair, chain.as_deref()).and_then(|(pair, store)| {
+		get_prices(
+			&pair,
+			store,
+			None,
+			1,
+			(COLLECTION_INTERVAL.std_duration().as_secs() / 60) as u16,
+			match at {
+				Some(timestamp) => Some(timestamp),
+				None => None,
+			},
+			true,
+		)
+	}) {
 		Ok(mut prices) => {
 			prices.reverse();
 			HttpResponse::Ok().json(prices)
@@ -151,6 +311,203 @@ async fn current(
 }
 
 #[get("/olhc/{pair}")]
-async fn olhc() -> impl Responder {
-	HttpResponse::InternalServerError().body("todo!")
+async fn olhc(
+	pair: Path<String>,
+	interval: Query<PricesQueryInfo>,
+	stores: Data<ChainStores>,
+) -> impl Responder {
+	let pair = pair.into_inner();
+	let PricesQueryInfo {
+		interval,
+		amount,
+		at,
+		source,
+		synthetic,
+		chain,
+	} = interval.into_inner();
+	match get_pair(&stores, pair, chain.as_deref()).and_then(|(pair, store)| {
+		get_candles(
+			&pair,
+			store,
+			source.as_deref(),
+			u16::min(MAX_DATAPOINTS, amount.unwrap_or(u16::MAX)),
+			interval,
+			at,
+			synthetic.unwrap_or(true),
+		)
+	}) {
+		Ok(mut candles) => {
+			candles.reverse();
+			HttpResponse::Ok().json(candles)
+		}
+		Err(error) => HttpResponse::BadRequest().json(ErrorValue {
+			error: error.to_string(),
+		}),
+	}
+}
+
+#[get("/rpc")]
+async fn rpc_stats(clients: Data<RpcClients>) -> impl Responder {
+	let stats: HashMap<String, Vec<EndpointStats>> = clients
+		.iter()
+		.map(|(chain, client)| (chain.to_string(), client.stats()))
+		.collect();
+
+	HttpResponse::Ok().json(stats)
+}
+
+#[derive(Deserialize)]
+struct TradesQueryInfo {
+	from: Option<i64>,
+	to: Option<i64>,
+	chain: Option<String>,
+}
+
+#[get("/trades/{pair}")]
+async fn trades(
+	pair: Path<String>,
+	query: Query<TradesQueryInfo>,
+	stores: Data<ChainStores>,
+) -> impl Responder {
+	let pair = pair.into_inner();
+	let TradesQueryInfo { from, to, chain } = query.into_inner();
+	match get_pair(&stores, pair, chain.as_deref())
+		.and_then(|(pair, store)| get_trades(&pair, store, from, to))
+	{
+		Ok(mut trades) => {
+			trades.reverse();
+			HttpResponse::Ok().json(trades)
+		}
+		Err(error) => HttpResponse::BadRequest().json(ErrorValue {
+			error: error.to_string(),
+		}),
+	}
+}
+
+#[derive(Deserialize)]
+struct TradeCandlesQueryInfo {
+	interval: u16,
+	amount: Option<u16>,
+	at: Option<i64>,
+	chain: Option<String>,
+}
+
+#[get("/trade_olhc/{pair}")]
+async fn trade_olhc(
+	pair: Path<String>,
+	query: Query<TradeCandlesQueryInfo>,
+	stores: Data<ChainStores>,
+) -> impl Responder {
+	let pair = pair.into_inner();
+	let TradeCandlesQueryInfo {
+		interval,
+		amount,
+		at,
+		chain,
+	} = query.into_inner();
+	match get_pair(&stores, pair, chain.as_deref()).and_then(|(pair, store)| {
+		get_trade_candles(
+			&pair,
+			store,
+			amount.map(|amount| u16::clamp(amount, 1, MAX_DATAPOINTS)),
+			interval,
+			at,
+		)
+	}) {
+		Ok(mut candles) => {
+			candles.reverse();
+			HttpResponse::Ok().json(candles)
+		}
+		Err(error) => HttpResponse::BadRequest().json(ErrorValue {
+			error: error.to_string(),
+		}),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use shared::coin::Pair;
+
+	use super::get_candles;
+	use crate::config;
+	use crate::datapoint::{Datapoint, Provenance, TimeType};
+	use crate::store::{MemoryStore, PriceStore};
+	use crate::testing::{datapoint, memory_store, START};
+
+	// three hours of prices collected every five minutes, the price being the slot
+	fn store() -> (MemoryStore, Pair) {
+		config::init_for_tests();
+		let (store, pair) = memory_store();
+		store
+			.store_prices(
+				&pair,
+				(0..36)
+					.map(|slot| datapoint(slot * 5, slot as f64))
+					.collect(),
+			)
+			.unwrap();
+
+		(store, pair)
+	}
+
+	#[test]
+	fn candles_are_bucketed_by_interval() {
+		let (store, pair) = store();
+
+		let candles = get_candles(&pair, &store, None, 2, 60, None, false).unwrap();
+		let candles: Vec<(i64, f64, f64, f64, f64)> = candles
+			.iter()
+			.map(|candle| {
+				(
+					candle.timestamp,
+					candle.open,
+					candle.high,
+					candle.low,
+					candle.close,
+				)
+			})
+			.collect();
+
+		assert_eq!(
+			candles,
+			[
+				(START + 60 * 60, 12.0, 23.0, 12.0, 23.0),
+				(START + 2 * 60 * 60, 24.0, 35.0, 24.0, 35.0)
+			]
+		);
+	}
+
+	#[test]
+	fn oldest_candle_is_not_cut_short() {
+		let (store, pair) = store();
+
+		// halfway through the last hour
+		let at = START + 2 * 60 * 60 + 30 * 60;
+		let candles = get_candles(&pair, &store, None, 2, 60, Some(at), false).unwrap();
+
+		assert_eq!(candles.len(), 2);
+		assert_eq!((candles[0].open, candles[0].close), (12.0, 23.0));
+		assert_eq!((candles[1].open, candles[1].close), (24.0, 30.0));
+	}
+
+	#[test]
+	fn synthetic_datapoints_are_left_out() {
+		let (store, pair) = store();
+		store
+			.insert_prices(
+				&pair,
+				vec![
+					Datapoint::new(1000.0, TimeType::Timestamp(START + 36 * 5 * 60))
+						.unwrap()
+						.with_provenance(Provenance::Interpolated),
+				],
+			)
+			.unwrap();
+
+		let candles = get_candles(&pair, &store, None, 1, 60, None, false).unwrap();
+		assert_eq!(candles[0].high, 35.0);
+
+		let candles = get_candles(&pair, &store, None, 1, 60, None, true).unwrap();
+		assert_eq!(candles[0].high, 1000.0);
+	}
 }
@@ -0,0 +1,125 @@
+use ethers::prelude::*;
+use eyre::{eyre, OptionExt, Result};
+use tracing::{debug, error, info, instrument, warn};
+
+use crate::config::PairConfig;
+use crate::datapoint::{BlockInfo, Datapoint, Provenance, TimeType};
+use crate::price::fetch_block;
+use crate::source::{aggregate_prices, PriceSource};
+
+async fn block_at<P: JsonRpcClient>(provider: &Provider<P>, number: u64) -> Result<BlockInfo> {
+	let block = provider
+		.get_block(number)
+		.await?
+		.ok_or_eyre(format!("block {} was not found", number))?;
+
+	Ok(BlockInfo {
+		number,
+		hash: block.hash.ok_or_eyre("block has no hash")?,
+		timestamp: block.timestamp.as_u64() as i64,
+	})
+}
+
+/// binary searches for the last block mined at or before `timestamp`, between `low` and `high`
+async fn find_block<P: JsonRpcClient>(
+	provider: &Provider<P>,
+	timestamp: i64,
+	mut low: BlockInfo,
+	mut high: BlockInfo,
+) -> Result<BlockInfo> {
+	if timestamp < low.timestamp {
+		return Err(eyre!(
+			"timestamp {} is before block {}",
+			timestamp,
+			low.number
+		));
+	}
+
+	if timestamp >= high.timestamp {
+		return Ok(high);
+	}
+
+	while high.number - low.number > 1 {
+		let middle = block_at(provider, low.number + (high.number - low.number) / 2).await?;
+
+		if middle.timestamp <= timestamp {
+			low = middle;
+		} else {
+			high = middle;
+		}
+	}
+
+	Ok(low)
+}
+
+/// replays the on-chain sources at the blocks closest to each of the missing `timestamps`,
+/// so gaps are filled from the same sources as live collection. needs an archive node
+#[instrument(err, skip(provider, sources, timestamps), fields(pair = ?config.pair))]
+pub async fn backfill_datapoints<P>(
+	provider: &Provider<P>,
+	sources: &[Box<dyn PriceSource>],
+	config: &PairConfig,
+	mut timestamps: Vec<i64>,
+) -> Result<Vec<Datapoint>>
+where
+	P: JsonRpcClient + 'static,
+{
+	let sources: Vec<&Box<dyn PriceSource>> =
+		sources.iter().filter(|source| source.on_chain()).collect();
+	if sources.is_empty() {
+		return Err(eyre!("no on-chain sources to backfill from"));
+	}
+
+	timestamps.sort();
+
+	let latest = fetch_block(provider).await?;
+	// blocks are searched in order, so every search can start from the previous block
+	let mut low = block_at(provider, 0).await?;
+	let mut datapoints = vec![];
+
+	for timestamp in timestamps {
+		if timestamp > latest.timestamp {
+			warn!(
+				timestamp,
+				"timestamp is newer than the latest block, skipping"
+			);
+			continue;
+		}
+
+		// a failed lookup only loses its own timestamp, the next repair tries it again
+		let block = match find_block(provider, timestamp, low, latest).await {
+			Ok(block) => block,
+			Err(error) => {
+				error!(error = ?error, timestamp, "error finding block, skipping");
+				continue;
+			}
+		};
+		low = block;
+
+		let prices =
+			aggregate_prices(&sources, std::slice::from_ref(config), block.number.into()).await;
+		let Some(price) = prices.first() else {
+			warn!(
+				timestamp,
+				block = block.number,
+				"no source returned a price, skipping"
+			);
+			continue;
+		};
+
+		debug!(
+			timestamp,
+			block = block.number,
+			price = price.price,
+			"backfilled price"
+		);
+		datapoints.push(
+			Datapoint::new(price.price, TimeType::Timestamp(timestamp))?
+				.with_block(Some(block))
+				.with_provenance(Provenance::OnChain),
+		);
+	}
+
+	info!(count = datapoints.len(), "backfilled datapoints");
+	Ok(datapoints)
+}
@@ -0,0 +1,121 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use ethers::prelude::*;
+use eyre::{eyre, Result};
+use futures::future;
+use shared::coin::Pair;
+use tracing::{debug, error};
+
+use crate::config::PairConfig;
+
+pub const CHAINLINK_SOURCE: &str = "chainlink";
+
+/// longest heartbeat of the common chainlink feeds
+pub const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// chainlink aggregator answering the price of `pair.1` in units of `pair.0`
+#[derive(Clone, Copy, Debug)]
+pub struct ChainlinkFeed {
+	pub address: Address,
+	/// the feed answers at least this often, older answers are stale
+	pub heartbeat: Duration,
+}
+
+abigen!(
+	Aggregator,
+	r#"[
+		function decimals() external view returns (uint8)
+		function latestRoundData() external view returns (uint80 roundId, int256 answer, uint256 startedAt, uint256 updatedAt, uint80 answeredInRound)
+	]"#
+);
+
+async fn fetch_feed_price<M: Middleware + 'static>(
+	aggregator: Aggregator<M>,
+	heartbeat: Duration,
+	block: BlockId,
+	block_timestamp: U256,
+) -> Result<f64> {
+	let decimals = aggregator.decimals().block(block).call().await?;
+	let (round_id, answer, _, updated_at, answered_in_round) =
+		aggregator.latest_round_data().block(block).call().await?;
+
+	if answer <= I256::zero() {
+		return Err(eyre!("feed answered with a non-positive price"));
+	}
+
+	if updated_at.is_zero() {
+		return Err(eyre!("feed round is not complete"));
+	}
+
+	if answered_in_round < round_id {
+		return Err(eyre!(
+			"feed answer of round {} is carried over from round {}",
+			round_id,
+			answered_in_round
+		));
+	}
+
+	// compared to the block it was read at, so replayed answers are judged like live ones
+	let age = block_timestamp.saturating_sub(updated_at);
+	if age > U256::from(heartbeat.as_secs()) {
+		return Err(eyre!(
+			"feed answer is {}s old, longer than its heartbeat of {}s",
+			age,
+			heartbeat.as_secs()
+		));
+	}
+
+	Ok(answer.as_i128() as f64 / 10_f64.powi(decimals as i32))
+}
+
+/// fetches the chainlink answer at `block` for every pair that has a feed configured
+pub async fn fetch_chainlink_prices<P>(
+	provider: Provider<P>,
+	pairs: &[PairConfig],
+	block: BlockId,
+) -> Vec<(Pair, f64)>
+where
+	P: JsonRpcClient + 'static,
+{
+	let feeds: Vec<(Pair, ChainlinkFeed)> = pairs
+		.iter()
+		.filter_map(|config| Some((config.pair.clone(), config.chainlink_feed?)))
+		.collect();
+	if feeds.is_empty() {
+		return vec![];
+	}
+
+	let block_timestamp = match provider.get_block(block).await {
+		Ok(Some(block)) => block.timestamp,
+		Ok(None) => {
+			error!("block chainlink prices are read at was not found");
+			return vec![];
+		}
+		Err(error) => {
+			error!(error = ?error, "error getting block chainlink prices are read at");
+			return vec![];
+		}
+	};
+
+	let provider = Arc::new(provider);
+	future::join_all(feeds.into_iter().map(|(pair, feed)| {
+		let aggregator = Aggregator::new(feed.address, provider.clone());
+		async move {
+			let price = fetch_feed_price(aggregator, feed.heartbeat, block, block_timestamp).await?;
+			debug!(pair = ?pair, price, "fetched chainlink price");
+
+			Ok((pair, price))
+		}
+	}))
+	.await
+	.into_iter()
+	.filter_map(|x: Result<(Pair, f64)>| {
+		if let Err(ref error) = x {
+			error!(error = ?error, "error getting chainlink price");
+		}
+
+		x.ok()
+	})
+	.collect()
+}
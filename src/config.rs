@@ -0,0 +1,568 @@
+use std::collections::HashSet;
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use ethers::types::{Address, Chain};
+use eyre::{eyre, OptionExt, Result, WrapErr};
+use serde::Deserialize;
+use shared::coin::Pair;
+
+use crate::chainlink::{ChainlinkFeed, DEFAULT_HEARTBEAT};
+use crate::datapoint::{KrakenInterval, PriceField};
+use crate::fallback::{fallback_source, FallbackSource, FallbackSymbol, KRAKEN};
+use crate::interpolate::{CandleOptions, Interpolation};
+use crate::outlier::{OutlierFilter, OutlierReference};
+use crate::price::Route;
+use crate::rpc::RetryPolicy;
+use crate::source::SOURCE_NAMES;
+
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// collection settings for a single pair
+#[derive(Clone, Debug)]
+pub struct PairConfig {
+	pub pair: Pair,
+	pub route: Route,
+	pub chainlink_feed: Option<ChainlinkFeed>,
+	/// sources missing datapoints are repaired from, tried in order
+	pub fallbacks: Vec<FallbackSymbol>,
+	/// uniswap v3 pool the swaps of `pair` are read from, looked up from the factory if not set
+	pub pool: Option<Address>,
+}
+
+impl PairConfig {
+	/// kraken is the first fallback source if the pair has a fallback name
+	pub fn new(pair: Pair, route: Route) -> Self {
+		let fallbacks = match &pair.2 {
+			Some(fallback_name) => vec![FallbackSymbol {
+				source: &KRAKEN,
+				symbol: fallback_name.clone(),
+			}],
+			None => vec![],
+		};
+
+		Self {
+			pair,
+			route,
+			chainlink_feed: None,
+			fallbacks,
+			pool: None,
+		}
+	}
+
+	pub fn with_chainlink_feed(mut self, feed: ChainlinkFeed) -> Self {
+		self.chainlink_feed = Some(feed);
+		self
+	}
+
+	pub fn with_pool(mut self, pool: Address) -> Self {
+		self.pool = Some(pool);
+		self
+	}
+
+	pub fn with_fallback(mut self, source: &'static dyn FallbackSource, symbol: &str) -> Self {
+		self.fallbacks.push(FallbackSymbol {
+			source,
+			symbol: symbol.to_string(),
+		});
+		self
+	}
+
+	/// symbol `pair` is traded under on the fallback source called `name`
+	pub fn fallback_symbol(&self, name: &str) -> Option<&str> {
+		self
+			.fallbacks
+			.iter()
+			.find(|fallback| fallback.source.name() == name)
+			.map(|fallback| fallback.symbol.as_str())
+	}
+}
+
+/// a chain prices are collected on
+#[derive(Clone, Debug)]
+pub struct ChainConfig {
+	pub chain: Chain,
+	/// tried in order, see `FailoverClient`
+	pub rpc_urls: Vec<String>,
+	/// uniswap v3 quoter the pairs are quoted with
+	pub quoter: Address,
+	/// uniswap v3 factory pools are looked up from
+	pub factory: Address,
+	/// websocket endpoint swaps are streamed from, no swaps are collected without one
+	pub ws_url: Option<String>,
+	pub pairs: Vec<PairConfig>,
+}
+
+/// where prices are stored, chains share the sqlite database and redis server
+#[derive(Clone, Debug)]
+pub enum StoreConfig {
+	Sqlite(PathBuf),
+	Redis(String),
+	/// nothing survives a restart
+	Memory,
+}
+
+/// validated contents of the config file
+#[derive(Clone, Debug)]
+pub struct Config {
+	/// the first chain is the one the api answers for when no chain is asked for
+	pub chains: Vec<ChainConfig>,
+	pub collection_interval: KrakenInterval,
+	/// how often missing datapoints are looked for and fixed
+	pub repair_interval: Duration,
+	pub bind_address: SocketAddr,
+	pub rpc: RetryPolicy,
+	/// collected prices that deviate too much from their reference are quarantined
+	pub outlier: OutlierFilter,
+	/// how fallback candles are priced and filled in down to the collection interval
+	pub candle_options: CandleOptions,
+	/// whether gaps are filled by replaying the on-chain sources on an archive node instead of
+	/// from the fallback sources
+	pub archive_backfill: bool,
+	pub store: StoreConfig,
+}
+
+impl Config {
+	/// reads the config file at CONFIG_PATH (config.toml by default), applies the env overrides and
+	/// validates it, has to be called once before `current`
+	pub fn init() -> Result<&'static Config> {
+		let path = env::var("CONFIG_PATH").unwrap_or(DEFAULT_CONFIG_PATH.to_string());
+		let contents =
+			fs::read_to_string(&path).wrap_err_with(|| format!("error reading config file {}", path))?;
+		let file: ConfigFile =
+			toml::from_str(&contents).wrap_err_with(|| format!("error parsing config file {}", path))?;
+		let config = file
+			.validate()
+			.wrap_err_with(|| format!("invalid config file {}", path))?;
+
+		CONFIG
+			.set(config)
+			.map_err(|_| eyre!("config was already initialized"))?;
+		Ok(current())
+	}
+}
+
+/// the config loaded by `Config::init`
+pub fn current() -> &'static Config {
+	CONFIG.get().expect("config should be initialized")
+}
+
+/// config collecting every five minutes on no chains, for tests that need `current`
+#[cfg(test)]
+pub fn init_for_tests() -> &'static Config {
+	CONFIG.get_or_init(|| Config {
+		chains: vec![],
+		collection_interval: KrakenInterval::FiveMinutes,
+		repair_interval: Duration::from_secs(default_repair_interval()),
+		bind_address: ([127, 0, 0, 1], 80).into(),
+		rpc: RetryPolicy::default(),
+		outlier: OutlierFilter::default(),
+		candle_options: CandleOptions::default(),
+		archive_backfill: false,
+		store: StoreConfig::Memory,
+	})
+}
+
+// parsed value of the env var `name`, if it is set
+fn env_override<T>(name: &str) -> Result<Option<T>>
+where
+	T: FromStr,
+	T::Err: Display,
+{
+	match env::var(name) {
+		Ok(value) => value
+			.parse()
+			.map(Some)
+			.map_err(|error| eyre!("{} is not valid: {}", name, error)),
+		Err(_) => Ok(None),
+	}
+}
+
+fn default_collection_interval() -> u64 {
+	5 * 60
+}
+
+fn default_repair_interval() -> u64 {
+	60 * 60
+}
+
+// same address on mainnet, arbitrum, optimism and polygon
+fn default_factory_address() -> Address {
+	"0x1F98431c8aD98523631AE4a59f267346ea31F984"
+		.parse()
+		.expect("uniswap v3 factory should be a valid address")
+}
+
+fn default_bind_address() -> String {
+	"127.0.0.1:80".to_string()
+}
+
+// the config file as written, see config.toml
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+	/// seconds, overridden by COLLECTION_INTERVAL
+	#[serde(default = "default_collection_interval")]
+	collection_interval: u64,
+	/// seconds, overridden by REPAIR_INTERVAL
+	#[serde(default = "default_repair_interval")]
+	repair_interval: u64,
+	/// overridden by BIND_ADDRESS
+	#[serde(default = "default_bind_address")]
+	bind_address: String,
+	#[serde(default)]
+	rpc: RetryPolicy,
+	/// overridden by OUTLIER_MAX_DEVIATION and OUTLIER_REFERENCE
+	#[serde(default)]
+	outlier: OutlierFilter,
+	/// close, vwap or typical, overridden by PRICE_FIELD
+	price_field: Option<String>,
+	/// step, linear, monotone or bridge, overridden by INTERPOLATION
+	interpolation: Option<String>,
+	/// fallback or archive, overridden by BACKFILL_SOURCE
+	backfill_source: Option<String>,
+	/// overridden by SQLITE_PATH, used over redis if both are set
+	sqlite_path: Option<PathBuf>,
+	/// overridden by REDIS_URI
+	redis_uri: Option<String>,
+	/// keeps prices in memory only, overridden by MEMORY_STORE
+	#[serde(default)]
+	memory_store: bool,
+	#[serde(default)]
+	chains: Vec<ChainFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChainFile {
+	chain: String,
+	/// tried in order, overridden by {CHAIN}_RPC_URLS (comma separated)
+	#[serde(default)]
+	rpc_urls: Vec<String>,
+	/// overridden by {CHAIN}_QUOTER_ADDRESS
+	quoter_address: Option<Address>,
+	#[serde(default = "default_factory_address")]
+	factory_address: Address,
+	/// overridden by {CHAIN}_WS_URL
+	ws_url: Option<String>,
+	#[serde(default)]
+	pairs: Vec<PairFile>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PairFile {
+	pair: String,
+	#[serde(default)]
+	route: Route,
+	chainlink_feed: Option<Address>,
+	/// seconds, defaults to a day
+	chainlink_heartbeat: Option<u64>,
+	pool: Option<Address>,
+	/// defaults to kraken if the pair has a kraken name
+	fallbacks: Option<Vec<FallbackFile>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FallbackFile {
+	source: String,
+	symbol: String,
+}
+
+impl ConfigFile {
+	fn validate(self) -> Result<Config> {
+		let collection_interval_secs =
+			env_override("COLLECTION_INTERVAL")?.unwrap_or(self.collection_interval);
+		let collection_interval = KrakenInterval::ALL
+			.into_iter()
+			.find(|interval| *interval as u64 * 60 == collection_interval_secs)
+			.ok_or_else(|| {
+				eyre!(
+					"collection interval of {}s is not one of {}",
+					collection_interval_secs,
+					KrakenInterval::ALL
+						.map(|interval| format!("{}s", interval as u64 * 60))
+						.join(", ")
+				)
+			})?;
+
+		let repair_interval = env_override("REPAIR_INTERVAL")?.unwrap_or(self.repair_interval);
+		if repair_interval == 0 {
+			return Err(eyre!("repair interval has to be at least a second"));
+		}
+
+		let bind_address = env_override("BIND_ADDRESS")?
+			.unwrap_or(self.bind_address)
+			.parse()
+			.wrap_err("bind address should be an ip address and port, like 127.0.0.1:80")?;
+
+		if self.rpc.attempts == 0 || self.rpc.failure_threshold == 0 {
+			return Err(eyre!(
+				"rpc attempts and failure threshold have to be at least 1"
+			));
+		}
+
+		let outlier = OutlierFilter::new(
+			env_override("OUTLIER_MAX_DEVIATION")?.unwrap_or(self.outlier.max_deviation),
+			env_override("OUTLIER_REFERENCE")?.unwrap_or(self.outlier.reference),
+		);
+		if !outlier.max_deviation.is_finite() || outlier.max_deviation <= 0.0 {
+			return Err(eyre!(
+				"outlier max deviation has to be above 0, like 0.05 for 5%"
+			));
+		}
+
+		if let OutlierReference::Source(source) = &outlier.reference {
+			if !SOURCE_NAMES.contains(&source.as_str()) {
+				return Err(eyre!(
+					"unknown outlier reference {}, expected previous or one of {}",
+					source,
+					SOURCE_NAMES.join(", ")
+				));
+			}
+		}
+
+		let price_field = env_override("PRICE_FIELD")?
+			.or(self.price_field)
+			.map(|field| field.parse::<PriceField>())
+			.transpose()
+			.wrap_err("price field should be one of close, vwap or typical")?
+			.unwrap_or_default();
+		let interpolation = env_override("INTERPOLATION")?
+			.or(self.interpolation)
+			.map(|interpolation| interpolation.parse::<Interpolation>())
+			.transpose()
+			.wrap_err("interpolation should be one of step, linear, monotone or bridge")?
+			.unwrap_or_default();
+
+		let archive_backfill = match env_override("BACKFILL_SOURCE")?
+			.or(self.backfill_source)
+			.as_deref()
+		{
+			None | Some("fallback") => false,
+			Some("archive") => true,
+			Some(source) => {
+				return Err(eyre!(
+					"unknown backfill source {}, expected fallback or archive",
+					source
+				))
+			}
+		};
+
+		let store = match (
+			env_override("SQLITE_PATH")?.or(self.sqlite_path),
+			env_override::<String>("REDIS_URI")?.or(self.redis_uri),
+			env_override("MEMORY_STORE")?.unwrap_or(self.memory_store),
+		) {
+			(Some(_), _, true) | (_, Some(_), true) => {
+				return Err(eyre!(
+					"memory store is enabled next to sqlite_path or redis_uri, only one store can be used"
+				))
+			}
+			(Some(sqlite_path), _, false) => StoreConfig::Sqlite(sqlite_path),
+			(None, Some(redis_uri), false) => {
+				redis::parse_redis_url(&redis_uri)
+					.ok_or_else(|| eyre!("redis uri {} is not valid", redis_uri))?;
+				StoreConfig::Redis(redis_uri)
+			}
+			(None, None, true) => StoreConfig::Memory,
+			(None, None, false) => {
+				return Err(eyre!(
+					"no store configured, set sqlite_path or redis_uri, or memory_store to keep prices in memory only"
+				))
+			}
+		};
+
+		let chains = self
+			.chains
+			.into_iter()
+			.map(ChainFile::validate)
+			.collect::<Result<Vec<ChainConfig>>>()?;
+
+		if chains.is_empty() {
+			return Err(eyre!("no chain configured"));
+		}
+
+		let mut seen = HashSet::new();
+		if let Some(duplicate) = chains.iter().find(|config| !seen.insert(config.chain)) {
+			return Err(eyre!("{} is configured twice", duplicate.chain));
+		}
+
+		Ok(Config {
+			chains,
+			collection_interval,
+			repair_interval: Duration::from_secs(repair_interval),
+			bind_address,
+			rpc: self.rpc,
+			outlier,
+			candle_options: CandleOptions {
+				price_field,
+				interpolation,
+			},
+			archive_backfill,
+			store,
+		})
+	}
+}
+
+impl ChainFile {
+	fn validate(self) -> Result<ChainConfig> {
+		let chain: Chain = self
+			.chain
+			.parse()
+			.map_err(|_| eyre!("unknown chain {}", self.chain))?;
+		let env_prefix = chain.to_string().to_uppercase().replace('-', "_");
+
+		let rpc_urls: Vec<String> = match env::var(format!("{}_RPC_URLS", env_prefix)) {
+			Ok(rpc_urls) => rpc_urls
+				.split(',')
+				.map(|url| url.trim().to_string())
+				.collect(),
+			Err(_) => self.rpc_urls,
+		};
+		let rpc_urls = match (rpc_urls.is_empty(), env::var("INFURA_SECRET")) {
+			// the collector used to only run on mainnet through infura
+			(true, Ok(secret)) if chain == Chain::Mainnet => {
+				vec![format!("https://mainnet.infura.io/v3/{}", secret)]
+			}
+			(true, _) => {
+				return Err(eyre!(
+					"no rpc urls for {}, set rpc_urls or {}_RPC_URLS",
+					chain,
+					env_prefix
+				))
+			}
+			(false, _) => rpc_urls,
+		};
+		for rpc_url in rpc_urls.iter() {
+			reqwest::Url::parse(rpc_url)
+				.wrap_err_with(|| format!("rpc url {} of {} is not valid", rpc_url, chain))?;
+		}
+
+		let quoter = match env_override(&format!("{}_QUOTER_ADDRESS", env_prefix))? {
+			Some(quoter) => Some(quoter),
+			None if chain == Chain::Mainnet => env_override("QUOTER_ADDRESS")?,
+			None => None,
+		}
+		.or(self.quoter_address)
+		.ok_or_else(|| {
+			eyre!(
+				"no quoter address for {}, set quoter_address or {}_QUOTER_ADDRESS",
+				chain,
+				env_prefix
+			)
+		})?;
+
+		let ws_url = env_override(&format!("{}_WS_URL", env_prefix))?.or(self.ws_url);
+		if let Some(ws_url) = &ws_url {
+			let scheme = reqwest::Url::parse(ws_url)
+				.wrap_err_with(|| format!("websocket url of {} is not valid", chain))?
+				.scheme()
+				.to_string();
+
+			if scheme != "ws" && scheme != "wss" {
+				return Err(eyre!(
+					"websocket url of {} has to start with ws:// or wss://",
+					chain
+				));
+			}
+		}
+
+		let pairs = self
+			.pairs
+			.into_iter()
+			.map(|pair| pair.validate(chain))
+			.collect::<Result<Vec<PairConfig>>>()
+			.wrap_err_with(|| format!("invalid pair on {}", chain))?;
+
+		let mut seen = HashSet::new();
+		if let Some(duplicate) = pairs
+			.iter()
+			.find(|config| !seen.insert(config.pair.to_string()))
+		{
+			return Err(eyre!("{} is configured twice on {}", duplicate.pair, chain));
+		}
+
+		Ok(ChainConfig {
+			chain,
+			rpc_urls,
+			quoter,
+			factory: self.factory_address,
+			ws_url,
+			pairs,
+		})
+	}
+}
+
+impl PairFile {
+	fn validate(self, chain: Chain) -> Result<PairConfig> {
+		let pair = Pair::get_pair(&self.pair, Some(chain.into()))
+			.ok_or_else(|| eyre!("unknown pair {}", self.pair))?;
+
+		if let Route::Path(hops) = &self.route {
+			let last_hop = hops
+				.last()
+				.ok_or_eyre(format!("route of {} has no hops", self.pair))?;
+
+			if last_hop.token != pair.0.address {
+				return Err(eyre!(
+					"route of {} has to end in {}",
+					self.pair,
+					pair.0.name
+				));
+			}
+		}
+
+		let mut config = PairConfig::new(pair, self.route);
+		match (self.chainlink_feed, self.chainlink_heartbeat) {
+			(_, Some(0)) => {
+				return Err(eyre!(
+					"chainlink heartbeat of {} has to be at least a second",
+					self.pair
+				))
+			}
+			(None, Some(_)) => {
+				return Err(eyre!(
+					"chainlink heartbeat of {} is set without a feed",
+					self.pair
+				))
+			}
+			(Some(address), heartbeat) => {
+				config = config.with_chainlink_feed(ChainlinkFeed {
+					address,
+					heartbeat: heartbeat.map_or(DEFAULT_HEARTBEAT, Duration::from_secs),
+				});
+			}
+			(None, None) => {}
+		}
+
+		if let Some(pool) = self.pool {
+			config = config.with_pool(pool);
+		}
+
+		if let Some(fallbacks) = self.fallbacks {
+			config.fallbacks = vec![];
+			for FallbackFile { source, symbol } in fallbacks {
+				let source = fallback_source(&source).ok_or_else(|| {
+					eyre!(
+						"unknown fallback source {} for {}, expected kraken, coinbase or binance",
+						source,
+						self.pair
+					)
+				})?;
+				config = config.with_fallback(source, &symbol);
+			}
+		}
+
+		Ok(config)
+	}
+}
@@ -1,10 +1,13 @@
+use std::str::FromStr;
+
 use chrono::{DateTime, Utc};
-use eyre::Result;
+use ethers::types::H256;
+use eyre::{eyre, Report, Result};
 use serde::{Deserialize, Serialize};
 
 pub const KRAKEN_MAX_DATAPOINTS: u16 = 720;
 
-#[derive(Deserialize, Copy, Clone, Debug)]
+#[derive(Serialize, Deserialize, Copy, Clone, Debug)]
 pub struct KrakenDatapoint {
 	pub timestamp: i64,
 	pub open: f32,
@@ -16,6 +19,47 @@ pub struct KrakenDatapoint {
 	pub count: u16,
 }
 
+/// candle field that becomes the price of a datapoint
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PriceField {
+	Close,
+	/// volume weighted average price
+	Vwap,
+	/// average of high, low and close
+	Typical,
+}
+
+impl Default for PriceField {
+	fn default() -> Self {
+		PriceField::Close
+	}
+}
+
+impl FromStr for PriceField {
+	type Err = Report;
+
+	fn from_str(field: &str) -> Result<Self> {
+		match field {
+			"close" => Ok(PriceField::Close),
+			"vwap" => Ok(PriceField::Vwap),
+			"typical" => Ok(PriceField::Typical),
+			_ => Err(eyre!("unknown price field {}", field)),
+		}
+	}
+}
+
+impl KrakenDatapoint {
+	pub fn price(&self, field: PriceField) -> f64 {
+		match field {
+			PriceField::Close => self.close as f64,
+			// candles without trades have no vwap
+			PriceField::Vwap if self.vwap > 0.0 => self.vwap as f64,
+			PriceField::Vwap => self.close as f64,
+			PriceField::Typical => (self.high as f64 + self.low as f64 + self.close as f64) / 3.0,
+		}
+	}
+}
+
 #[derive(Deserialize, Copy, Clone, Debug)]
 pub enum KrakenInterval {
 	Minute = 1,
@@ -29,16 +73,164 @@ pub enum KrakenInterval {
 	HalfMonth = 21600,
 }
 
+impl KrakenInterval {
+	/// every interval, sorted from smallest to largest
+	pub const ALL: [KrakenInterval; 9] = [
+		KrakenInterval::Minute,
+		KrakenInterval::FiveMinutes,
+		KrakenInterval::FifteenMinutes,
+		KrakenInterval::HalfHour,
+		KrakenInterval::Hour,
+		KrakenInterval::FourHours,
+		KrakenInterval::Day,
+		KrakenInterval::Week,
+		KrakenInterval::HalfMonth,
+	];
+}
+
 impl Default for KrakenInterval {
 	fn default() -> Self {
 		KrakenInterval::FiveMinutes
 	}
 }
 
+/// block an on-chain price was read at
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct BlockInfo {
+	pub number: u64,
+	pub hash: H256,
+	pub timestamp: i64,
+}
+
+/// where the price of a datapoint comes from
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Provenance {
+	/// read from the chain, live or replayed at a historical block
+	OnChain,
+	/// close of a kraken candle
+	Kraken,
+	/// close of a coinbase candle
+	Coinbase,
+	/// close of a binance candle
+	Binance,
+	/// median of on-chain and off-chain source prices, so not tied to a block
+	Aggregated,
+	/// synthesized between two other datapoints
+	Interpolated,
+	/// stored before provenance was tracked
+	Unknown,
+}
+
+impl Default for Provenance {
+	fn default() -> Self {
+		Provenance::Unknown
+	}
+}
+
+impl Provenance {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Provenance::OnChain => "onchain",
+			Provenance::Kraken => "kraken",
+			Provenance::Coinbase => "coinbase",
+			Provenance::Binance => "binance",
+			Provenance::Aggregated => "aggregated",
+			Provenance::Interpolated => "interpolated",
+			Provenance::Unknown => "unknown",
+		}
+	}
+
+	/// whether the price was made up instead of observed
+	pub fn is_synthetic(&self) -> bool {
+		matches!(self, Provenance::Interpolated)
+	}
+}
+
+impl FromStr for Provenance {
+	type Err = Report;
+
+	fn from_str(provenance: &str) -> Result<Self> {
+		match provenance {
+			"onchain" => Ok(Provenance::OnChain),
+			"kraken" => Ok(Provenance::Kraken),
+			"coinbase" => Ok(Provenance::Coinbase),
+			"binance" => Ok(Provenance::Binance),
+			"aggregated" => Ok(Provenance::Aggregated),
+			"interpolated" => Ok(Provenance::Interpolated),
+			"unknown" => Ok(Provenance::Unknown),
+			_ => Err(eyre!("unknown provenance {}", provenance)),
+		}
+	}
+}
+
+/// fallback candle a datapoint was taken from
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct SourceCandle {
+	/// length of the candle in minutes
+	pub interval: u16,
+	#[serde(flatten)]
+	pub candle: KrakenDatapoint,
+}
+
+/// swaps in the pool of a pair over a range of blocks, amounts are in whole coins of the pair
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct Volume {
+	/// amount of `pair.1` swapped in either direction
+	pub base: f64,
+	/// amount of `pair.0` swapped in either direction
+	pub quote: f64,
+	pub trades: u32,
+	pub from_block: u64,
+	pub to_block: u64,
+}
+
+impl Volume {
+	pub fn new(from_block: u64, to_block: u64) -> Self {
+		Self {
+			from_block,
+			to_block,
+			..Self::default()
+		}
+	}
+
+	/// counts a swap of `base_amount` against `quote_amount`, whichever way it went
+	pub fn add_swap(&mut self, base_amount: f64, quote_amount: f64) {
+		self.base += base_amount.abs();
+		self.quote += quote_amount.abs();
+		self.trades += 1;
+	}
+
+	/// both volumes over the blocks they cover together
+	pub fn merge(self, other: Volume) -> Self {
+		Self {
+			base: self.base + other.base,
+			quote: self.quote + other.quote,
+			trades: self.trades + other.trades,
+			from_block: self.from_block.min(other.from_block),
+			to_block: self.to_block.max(other.to_block),
+		}
+	}
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Datapoint {
 	pub price: f64,
 	pub timestamp: i64,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub block: Option<BlockInfo>,
+	#[serde(default)]
+	pub provenance: Provenance,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub candle: Option<SourceCandle>,
+	/// on-chain volume since the previous collection, only known for collected prices of pairs
+	/// with a pool
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub volume: Option<Volume>,
+	/// fee tier of every pool a quoted price went through, in hundredths of a bip. only known for
+	/// prices of the quoter source, where it shows which pool the best fee selection picked
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub fees: Option<Vec<u32>>,
 }
 
 #[derive(Clone, Debug, Copy)]
@@ -54,6 +246,160 @@ impl Datapoint {
 			TimeType::Timestamp(timestamp) => timestamp,
 		};
 
-		Ok(Self { price, timestamp })
+		Ok(Self {
+			price,
+			timestamp,
+			block: None,
+			provenance: Provenance::default(),
+			candle: None,
+			volume: None,
+			fees: None,
+		})
+	}
+
+	pub fn with_block(mut self, block: Option<BlockInfo>) -> Self {
+		self.block = block;
+		self
+	}
+
+	pub fn with_provenance(mut self, provenance: Provenance) -> Self {
+		self.provenance = provenance;
+		self
+	}
+
+	pub fn with_candle(mut self, candle: Option<SourceCandle>) -> Self {
+		self.candle = candle;
+		self
+	}
+
+	pub fn with_volume(mut self, volume: Option<Volume>) -> Self {
+		self.volume = volume;
+		self
+	}
+
+	pub fn with_fees(mut self, fees: Option<Vec<u32>>) -> Self {
+		self.fees = fees;
+		self
+	}
+}
+
+/// a single swap in the pool of a pair, amounts are in whole coins of the pair
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Trade {
+	pub timestamp: i64,
+	pub block_number: u64,
+	pub transaction_hash: H256,
+	pub log_index: u64,
+	/// price of `pair.1` in units of `pair.0` right after the swap
+	pub price: f64,
+	/// amount of `pair.1` taken out of the pool, negative if it was put in
+	pub base_amount: f64,
+	/// amount of `pair.0` taken out of the pool, negative if it was put in
+	pub quote_amount: f64,
+	pub tick: i32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Candle {
+	pub open: f64,
+	pub high: f64,
+	pub low: f64,
+	pub close: f64,
+	pub timestamp: i64,
+	/// volume of the fallback candles inside this candle, only known for backfilled ranges
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub volume: Option<f64>,
+	/// volume weighted average price, only known for candles built from trades
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub vwap: Option<f64>,
+	/// on-chain volume of the collected datapoints inside this candle
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub onchain_volume: Option<Volume>,
+}
+
+impl Candle {
+	/// builds a candle of `interval_secs` starting at `timestamp` from datapoints sorted oldest first,
+	/// fallback candles that fit inside it widen its high and low
+	pub fn from_datapoints(
+		timestamp: i64,
+		interval_secs: i64,
+		datapoints: &[Datapoint],
+	) -> Option<Self> {
+		let open = datapoints.first()?.price;
+		let close = datapoints.last()?.price;
+		let (high, low) = datapoints
+			.iter()
+			.fold((f64::MIN, f64::MAX), |(high, low), datapoint| {
+				(high.max(datapoint.price), low.min(datapoint.price))
+			});
+
+		let source_candles: Vec<&KrakenDatapoint> = datapoints
+			.iter()
+			.filter_map(|datapoint| datapoint.candle.as_ref())
+			.filter(|source| source.interval as i64 * 60 <= interval_secs)
+			.map(|source| &source.candle)
+			.collect();
+
+		let (high, low) = source_candles
+			.iter()
+			.fold((high, low), |(high, low), candle| {
+				(high.max(candle.high as f64), low.min(candle.low as f64))
+			});
+		let volume = match source_candles.is_empty() {
+			true => None,
+			false => Some(
+				source_candles
+					.iter()
+					.map(|candle| candle.volume as f64)
+					.sum(),
+			),
+		};
+
+		Some(Self {
+			open,
+			high,
+			low,
+			close,
+			timestamp,
+			volume,
+			vwap: None,
+			onchain_volume: datapoints
+				.iter()
+				.filter_map(|datapoint| datapoint.volume)
+				.reduce(Volume::merge),
+		})
+	}
+
+	/// builds a candle starting at `timestamp` from trades sorted oldest first, its volume is in
+	/// units of `pair.1`
+	pub fn from_trades(timestamp: i64, trades: &[Trade]) -> Option<Self> {
+		let open = trades.first()?.price;
+		let close = trades.last()?.price;
+		let (high, low) = trades
+			.iter()
+			.fold((f64::MIN, f64::MAX), |(high, low), trade| {
+				(high.max(trade.price), low.min(trade.price))
+			});
+
+		let (volume, quote_volume) = trades.iter().fold((0.0, 0.0), |(volume, quote), trade| {
+			(
+				volume + trade.base_amount.abs(),
+				quote + trade.quote_amount.abs(),
+			)
+		});
+
+		Some(Self {
+			open,
+			high,
+			low,
+			close,
+			timestamp,
+			volume: Some(volume),
+			vwap: match volume > 0.0 {
+				true => Some(quote_volume / volume),
+				false => None,
+			},
+			onchain_volume: None,
+		})
 	}
 }
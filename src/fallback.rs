@@ -0,0 +1,317 @@
+use std::fmt;
+use std::time::Duration;
+
+use eyre::{eyre, ContextCompat, OptionExt, Result};
+use futures::future::{BoxFuture, FutureExt};
+use lazy_static::lazy_static;
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::datapoint::{KrakenDatapoint, KrakenInterval, Provenance};
+use crate::fixes::fetch_kraken_candles;
+
+// requests that take longer are given up on, and retried like any other transient error
+const FALLBACK_TIMEOUT: Duration = Duration::from_secs(15);
+
+lazy_static! {
+	/// client every fallback source fetches its candles with
+	pub static ref FALLBACK_CLIENT: reqwest::Client = reqwest::Client::builder()
+		.timeout(FALLBACK_TIMEOUT)
+		// coinbase rejects requests without a user agent
+		.user_agent("price-collector")
+		.build()
+		.expect("fallback http client did not build properly");
+}
+
+/// exchange historical candles are fetched from to repair missing datapoints
+pub trait FallbackSource: Send + Sync {
+	fn name(&self) -> &'static str;
+
+	/// provenance of the datapoints repaired from this source
+	fn provenance(&self) -> Provenance;
+
+	/// whether candles of `interval` can be fetched
+	fn supports(&self, interval: KrakenInterval) -> bool;
+
+	/// latest candles of `symbol`, sorted oldest first
+	fn fetch_candles<'a>(
+		&'a self,
+		symbol: &'a str,
+		interval: KrakenInterval,
+	) -> BoxFuture<'a, Result<Vec<KrakenDatapoint>>>;
+}
+
+/// symbol a pair is traded under on a fallback source
+#[derive(Clone)]
+pub struct FallbackSymbol {
+	pub source: &'static dyn FallbackSource,
+	pub symbol: String,
+}
+
+impl fmt::Debug for FallbackSymbol {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}:{}", self.source.name(), self.symbol)
+	}
+}
+
+pub static KRAKEN: Kraken = Kraken;
+pub static COINBASE: Coinbase = Coinbase;
+pub static BINANCE: Binance = Binance;
+
+/// fallback source called `name`, as named in the config file
+pub fn fallback_source(name: &str) -> Option<&'static dyn FallbackSource> {
+	[&KRAKEN as &'static dyn FallbackSource, &COINBASE, &BINANCE]
+		.into_iter()
+		.find(|source| source.name() == name)
+}
+
+pub struct Kraken;
+
+impl FallbackSource for Kraken {
+	fn name(&self) -> &'static str {
+		"kraken"
+	}
+
+	fn provenance(&self) -> Provenance {
+		Provenance::Kraken
+	}
+
+	fn supports(&self, _interval: KrakenInterval) -> bool {
+		true
+	}
+
+	fn fetch_candles<'a>(
+		&'a self,
+		symbol: &'a str,
+		interval: KrakenInterval,
+	) -> BoxFuture<'a, Result<Vec<KrakenDatapoint>>> {
+		async move { fetch_kraken_candles(symbol, &interval).await }.boxed()
+	}
+}
+
+// parses a json number, or a number inside a json string
+fn parse_number(value: &Value) -> Result<f32> {
+	match value {
+		Value::String(value) => Ok(value.parse()?),
+		value => Ok(value.as_f64().wrap_err("candle value is not a number")? as f32),
+	}
+}
+
+// counts and logs the rows that did not parse, and returns the rest
+fn collect_candles(
+	source: &str,
+	rows: &[Value],
+	parse: impl Fn(&Value) -> Result<KrakenDatapoint>,
+) -> Vec<KrakenDatapoint> {
+	let candles: Vec<KrakenDatapoint> = rows
+		.iter()
+		.map(parse)
+		.filter_map(|x| {
+			if let Err(ref error) = x {
+				debug!(error = ?error, source, "skipping malformed candle");
+			}
+
+			x.ok()
+		})
+		.collect();
+
+	let skipped = rows.len() - candles.len();
+	if skipped > 0 {
+		warn!(
+			skipped,
+			total = rows.len(),
+			source,
+			"skipped malformed candles"
+		);
+	}
+
+	candles
+}
+
+/// coinbase exchange candles, at most 300 per request
+pub struct Coinbase;
+
+impl Coinbase {
+	fn granularity(interval: KrakenInterval) -> Option<u32> {
+		match interval {
+			KrakenInterval::Minute => Some(60),
+			KrakenInterval::FiveMinutes => Some(300),
+			KrakenInterval::FifteenMinutes => Some(900),
+			KrakenInterval::Hour => Some(3600),
+			KrakenInterval::Day => Some(86400),
+			_ => None,
+		}
+	}
+
+	// [time, low, high, open, close, volume]
+	fn parse_candle(row: &Value) -> Result<KrakenDatapoint> {
+		let (low, high, close) = (
+			parse_number(&row[1])?,
+			parse_number(&row[2])?,
+			parse_number(&row[4])?,
+		);
+
+		Ok(KrakenDatapoint {
+			timestamp: row[0].as_i64().wrap_err("coinbase candle has no time")?,
+			open: parse_number(&row[3])?,
+			high,
+			low,
+			close,
+			// coinbase has no vwap, the typical price is the closest approximation
+			vwap: (high + low + close) / 3.0,
+			volume: parse_number(&row[5])?,
+			count: 0,
+		})
+	}
+}
+
+impl FallbackSource for Coinbase {
+	fn name(&self) -> &'static str {
+		"coinbase"
+	}
+
+	fn provenance(&self) -> Provenance {
+		Provenance::Coinbase
+	}
+
+	fn supports(&self, interval: KrakenInterval) -> bool {
+		Self::granularity(interval).is_some()
+	}
+
+	fn fetch_candles<'a>(
+		&'a self,
+		symbol: &'a str,
+		interval: KrakenInterval,
+	) -> BoxFuture<'a, Result<Vec<KrakenDatapoint>>> {
+		async move {
+			let granularity =
+				Self::granularity(interval).ok_or_eyre("interval not supported by coinbase")?;
+
+			let response = FALLBACK_CLIENT
+				.get(format!(
+					"https://api.exchange.coinbase.com/products/{}/candles?granularity={}",
+					symbol, granularity
+				))
+				.send()
+				.await?;
+
+			let status = response.status();
+			let response = response.json::<Value>().await?;
+			if !status.is_success() {
+				return Err(eyre!(
+					"coinbase returned {}: {}",
+					status,
+					response["message"].as_str().unwrap_or("no message")
+				));
+			}
+
+			let rows = response
+				.as_array()
+				.wrap_err("coinbase response format was not correct")?;
+
+			// candles come newest first
+			let mut candles = collect_candles(self.name(), rows, Self::parse_candle);
+			candles.sort_by_key(|candle| candle.timestamp);
+
+			Ok(candles)
+		}
+		.boxed()
+	}
+}
+
+/// binance spot klines, at most 1000 per request
+pub struct Binance;
+
+impl Binance {
+	fn interval_name(interval: KrakenInterval) -> Option<&'static str> {
+		match interval {
+			KrakenInterval::Minute => Some("1m"),
+			KrakenInterval::FiveMinutes => Some("5m"),
+			KrakenInterval::FifteenMinutes => Some("15m"),
+			KrakenInterval::HalfHour => Some("30m"),
+			KrakenInterval::Hour => Some("1h"),
+			KrakenInterval::FourHours => Some("4h"),
+			KrakenInterval::Day => Some("1d"),
+			KrakenInterval::Week => Some("1w"),
+			KrakenInterval::HalfMonth => None,
+		}
+	}
+
+	// [open time (ms), open, high, low, close, volume, close time, quote volume, trades, ...]
+	fn parse_candle(row: &Value) -> Result<KrakenDatapoint> {
+		let (close, volume, quote_volume) = (
+			parse_number(&row[4])?,
+			parse_number(&row[5])?,
+			parse_number(&row[7])?,
+		);
+
+		Ok(KrakenDatapoint {
+			timestamp: row[0]
+				.as_i64()
+				.wrap_err("binance candle has no open time")?
+				/ 1000,
+			open: parse_number(&row[1])?,
+			high: parse_number(&row[2])?,
+			low: parse_number(&row[3])?,
+			close,
+			vwap: match volume > 0.0 {
+				true => quote_volume / volume,
+				false => close,
+			},
+			volume,
+			count: row[8]
+				.as_u64()
+				.wrap_err("binance candle has no trade count")? as u16,
+		})
+	}
+}
+
+impl FallbackSource for Binance {
+	fn name(&self) -> &'static str {
+		"binance"
+	}
+
+	fn provenance(&self) -> Provenance {
+		Provenance::Binance
+	}
+
+	fn supports(&self, interval: KrakenInterval) -> bool {
+		Self::interval_name(interval).is_some()
+	}
+
+	fn fetch_candles<'a>(
+		&'a self,
+		symbol: &'a str,
+		interval: KrakenInterval,
+	) -> BoxFuture<'a, Result<Vec<KrakenDatapoint>>> {
+		async move {
+			let interval_name =
+				Self::interval_name(interval).ok_or_eyre("interval not supported by binance")?;
+
+			let response = FALLBACK_CLIENT
+				.get(format!(
+					"https://api.binance.com/api/v3/klines?symbol={}&interval={}&limit=1000",
+					symbol, interval_name
+				))
+				.send()
+				.await?;
+
+			let status = response.status();
+			let response = response.json::<Value>().await?;
+			if !status.is_success() {
+				return Err(eyre!(
+					"binance returned {}: {}",
+					status,
+					response["msg"].as_str().unwrap_or("no message")
+				));
+			}
+
+			let rows = response
+				.as_array()
+				.wrap_err("binance response format was not correct")?;
+
+			Ok(collect_candles(self.name(), rows, Self::parse_candle))
+		}
+		.boxed()
+	}
+}
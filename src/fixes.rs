@@ -1,22 +1,32 @@
 use std::any::type_name;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::num::ParseFloatError;
-use std::ops::Mul;
+use std::ops::Range;
 use std::str::FromStr;
+use std::time::Duration;
 
 use chrono::{prelude::*, TimeDelta};
 use eyre::{eyre, ContextCompat, OptionExt, Result};
 use hhmmss::Hhmmss;
-use redis::Commands;
+use reqwest::StatusCode;
 use serde_json::Value;
 use shared::coin::Pair;
-use tracing::{debug, warn};
+use tracing::{debug, error, info, warn};
 
+use crate::config::PairConfig;
 use crate::datapoint::{
-	Datapoint, KrakenDatapoint, KrakenInterval, TimeType, KRAKEN_MAX_DATAPOINTS,
+	Datapoint, KrakenDatapoint, KrakenInterval, Provenance, KRAKEN_MAX_DATAPOINTS,
 };
-use crate::interpolate::interpolate_datapoints;
-use crate::COLLECTION_INTERVAL;
+use crate::fallback::{FallbackSource, FallbackSymbol, FALLBACK_CLIENT, KRAKEN};
+use crate::interpolate::{interpolate_candles, CandleOptions};
+use crate::store::PriceStore;
+use crate::{config, COLLECTION_INTERVAL};
+
+const KRAKEN_MAX_RETRIES: u32 = 3;
+const KRAKEN_RETRY_BACKOFF: Duration = Duration::from_secs(2);
+// candles of larger intervals are too coarse to make up collection interval prices from
+const MAX_REPAIR_INTERVAL: KrakenInterval = KrakenInterval::Day;
 
 fn convert_str_f32<T: FromStr<Err = ParseFloatError>>(value: &Value) -> Result<T> {
 	let type_name = type_name::<T>();
@@ -27,164 +37,406 @@ fn convert_str_f32<T: FromStr<Err = ParseFloatError>>(value: &Value) -> Result<T
 	Ok(value.parse()?)
 }
 
-pub fn find_discrepancies(client: &redis::Client, pair: &Pair) -> Result<Vec<i64>> {
-	let mut connection = client.get_connection()?;
-	debug!("redis connection established");
+// start of every collection interval from the first stored timestamp up to, but not including,
+// the interval `now` falls into (its price might not have been collected yet) that has no stored timestamp
+fn missing_timestamps(timestamps: &[i64], now: i64, interval_secs: i64) -> Vec<i64> {
+	let align = |timestamp: i64| timestamp - timestamp.rem_euclid(interval_secs);
+
+	let Some(first) = timestamps.iter().min() else {
+		return vec![];
+	};
+
+	let stored: HashSet<i64> = timestamps
+		.iter()
+		.map(|timestamp| align(*timestamp))
+		.collect();
+
+	(align(*first)..align(now))
+		.step_by(interval_secs as usize)
+		.filter(|timestamp| !stored.contains(timestamp))
+		.collect()
+}
+
+/// walks every stored timestamp of `pair` and returns the start of every collection interval
+/// without a price, both inside the stored series and between the last stored timestamp and now
+pub fn find_discrepancies(store: &dyn PriceStore, pair: &Pair) -> Result<Vec<i64>> {
+	let timestamps = store.get_timestamps(pair)?;
+	if timestamps.is_empty() {
+		return Err(eyre!("no stored timestamps to compare against"));
+	}
+
+	Ok(missing_timestamps(
+		&timestamps,
+		Utc::now().timestamp(),
+		COLLECTION_INTERVAL.std_duration().as_secs() as i64,
+	))
+}
 
-	let timestamp =
-		connection.lindex::<String, i64>(format!("{}:timestamps", pair.to_string()), -1)?;
+#[derive(Debug)]
+pub enum KrakenError {
+	RateLimited,
+	UnknownPair(String),
+	ServiceUnavailable,
+	Other(Vec<String>),
+}
 
-	let last_real_dt = DateTime::from_timestamp(timestamp, 0)
-		.ok_or_eyre("timestamp did not convert to NaiveDateTime")?
-		.to_utc()
-		.timestamp();
-	let next_collection_dt = Utc::now()
-		.trunc_subsecs(0)
-		.with_second(0)
-		.ok_or_eyre("could not set seconds to zero")?
-		.timestamp();
+impl KrakenError {
+	fn from_messages(pair: &str, messages: Vec<String>) -> Self {
+		let has = |prefix: &str| messages.iter().any(|message| message.starts_with(prefix));
+
+		if has("EAPI:Rate limit") || has("EGeneral:Too many requests") {
+			KrakenError::RateLimited
+		} else if has("EQuery:Unknown asset pair") {
+			KrakenError::UnknownPair(pair.to_string())
+		} else if has("EService:Unavailable") || has("EService:Busy") {
+			KrakenError::ServiceUnavailable
+		} else {
+			KrakenError::Other(messages)
+		}
+	}
 
-	let missing_points = (next_collection_dt - last_real_dt) / 60;
+	pub fn is_transient(&self) -> bool {
+		matches!(
+			self,
+			KrakenError::RateLimited | KrakenError::ServiceUnavailable
+		)
+	}
+}
 
-	Ok(
-		(0..missing_points)
-			.map(|t| last_real_dt + (COLLECTION_INTERVAL.std_duration().as_secs() as i64).mul(t + 1))
-			.collect(),
-	)
+impl fmt::Display for KrakenError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			KrakenError::RateLimited => write!(f, "kraken rate limit exceeded"),
+			KrakenError::UnknownPair(pair) => write!(f, "kraken does not know pair {}", pair),
+			KrakenError::ServiceUnavailable => write!(f, "kraken is unavailable"),
+			KrakenError::Other(messages) => write!(f, "kraken returned errors: {}", messages.join(", ")),
+		}
+	}
 }
 
-async fn fetch_kraken_datapoints(
-	pair: &Pair,
+impl std::error::Error for KrakenError {}
+
+fn is_transient(error: &eyre::Report) -> bool {
+	if let Some(error) = error.downcast_ref::<KrakenError>() {
+		return error.is_transient();
+	}
+
+	if let Some(error) = error.downcast_ref::<reqwest::Error>() {
+		return error.is_timeout()
+			|| error.is_connect()
+			|| error
+				.status()
+				.is_some_and(|status| status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS);
+	}
+
+	false
+}
+
+fn parse_kraken_datapoint(point: &Value) -> Result<KrakenDatapoint> {
+	let response_format_err_msg = "fallback datapoint format was not correct";
+
+	Ok(KrakenDatapoint {
+		timestamp: point[0].as_i64().wrap_err(response_format_err_msg)?,
+		open: convert_str_f32(&point[1])?,
+		high: convert_str_f32(&point[2])?,
+		low: convert_str_f32(&point[3])?,
+		close: convert_str_f32(&point[4])?,
+		vwap: convert_str_f32(&point[5])?,
+		volume: convert_str_f32(&point[6])?,
+		count: point[7].as_u64().wrap_err(response_format_err_msg)? as u16,
+	})
+}
+
+/// fetches kraken candles once, without retrying
+pub async fn try_fetch_kraken_datapoints(
+	client: &reqwest::Client,
+	fallback_name: &str,
 	interval: &KrakenInterval,
 ) -> Result<Vec<KrakenDatapoint>> {
-	let fallback_name = pair.2.clone().ok_or_eyre("no fallback name")?;
-	let response = reqwest::get(format!(
-		"https://api.kraken.com/0/public/OHLC?pair={}&interval={}",
-		fallback_name, *interval as u16
-	))
-	.await?
-	.json::<serde_json::Value>()
-	.await?;
+	let response = client
+		.get(format!(
+			"https://api.kraken.com/0/public/OHLC?pair={}&interval={}",
+			fallback_name, *interval as u16
+		))
+		.send()
+		.await?
+		.error_for_status()?
+		.json::<serde_json::Value>()
+		.await?;
 
 	let response_format_err_msg = "fallback response format was not correct";
 
-	let errors = response
+	let errors: Vec<String> = response
 		.get("error")
 		.wrap_err(response_format_err_msg)?
 		.as_array()
 		.wrap_err(response_format_err_msg)?
-		.to_owned();
+		.iter()
+		.filter_map(|error| error.as_str().map(String::from))
+		.collect();
 
-	if errors.len() > 0 {
-		todo!("error handling for kraken tbd");
+	if !errors.is_empty() {
+		return Err(KrakenError::from_messages(fallback_name, errors).into());
 	}
 
-	let datapoints: Vec<KrakenDatapoint> = response
+	let points = response
 		.get("result")
 		.wrap_err(response_format_err_msg)?
 		.get(fallback_name)
 		.wrap_err(response_format_err_msg)?
 		.as_array()
-		.wrap_err(response_format_err_msg)?
+		.wrap_err(response_format_err_msg)?;
+
+	let datapoints: Vec<KrakenDatapoint> = points
 		.iter()
-		.map(|point| KrakenDatapoint {
-			timestamp: point[0].as_i64().expect(response_format_err_msg),
-			open: convert_str_f32(&point[1]).expect(response_format_err_msg),
-			high: convert_str_f32(&point[2]).expect(response_format_err_msg),
-			low: convert_str_f32(&point[3]).expect(response_format_err_msg),
-			close: convert_str_f32(&point[4]).expect(response_format_err_msg),
-			vwap: convert_str_f32(&point[5]).expect(response_format_err_msg),
-			volume: convert_str_f32(&point[6]).expect(response_format_err_msg),
-			count: point[7].as_u64().expect(response_format_err_msg) as u16,
+		.map(parse_kraken_datapoint)
+		.filter_map(|x| {
+			if let Err(ref error) = x {
+				debug!(error = ?error, "skipping malformed kraken datapoint");
+			}
+
+			x.ok()
 		})
 		.collect();
 
+	let skipped = points.len() - datapoints.len();
+	if skipped > 0 {
+		warn!(
+			skipped,
+			total = points.len(),
+			"skipped malformed kraken datapoints"
+		);
+	}
+
 	Ok(datapoints)
 }
 
-pub async fn fix_discrepancies(pair: &Pair, datapoints: Vec<i64>) -> Result<Vec<Datapoint>> {
-	if datapoints.len() < 1 {
-		return Err(eyre!("no discrepancies"));
+/// fetches kraken candles, retrying with exponential backoff when kraken is rate limited or unavailable
+pub async fn fetch_kraken_candles(
+	fallback_name: &str,
+	interval: &KrakenInterval,
+) -> Result<Vec<KrakenDatapoint>> {
+	let mut attempt = 0;
+	loop {
+		match try_fetch_kraken_datapoints(&FALLBACK_CLIENT, fallback_name, interval).await {
+			Err(error) if attempt < KRAKEN_MAX_RETRIES && is_transient(&error) => {
+				let backoff = KRAKEN_RETRY_BACKOFF * 2_u32.pow(attempt);
+				warn!(error = ?error, attempt, backoff = ?backoff, "transient kraken error, retrying");
+
+				tokio::time::sleep(backoff).await;
+				attempt += 1;
+			}
+			result => return result,
+		}
 	}
+}
 
-	let outage_time = COLLECTION_INTERVAL
-		.std_duration()
-		.checked_mul(datapoints.len() as u32)
-		.ok_or_eyre("outage time calcuation overflowed")?;
-	let outage_time_minutes = (outage_time.as_secs_f32() / 60.0).floor() as u32;
+// interpolated timestamps can be a little off, so fixed datapoints are matched by minute
+fn round_to_minute(timestamp: i64) -> i64 {
+	(timestamp + 30).div_euclid(60) * 60
+}
 
-	let interval = KrakenInterval::default();
-	let max_minutes_in_interval = interval as u16 * KRAKEN_MAX_DATAPOINTS as u16;
+// ranges of consecutive candles (sorted oldest first) that start, end or lie between the
+// `missing` timestamps (sorted), the candles in between would only be interpolated to be thrown away
+fn bracketing_runs(candles: &[KrakenDatapoint], missing: &[i64]) -> Vec<Range<usize>> {
+	// interpolated timestamps are matched by minute
+	let brackets = |from: i64, to: i64| {
+		let index = missing.partition_point(|timestamp| *timestamp < from - 60);
+		missing
+			.get(index)
+			.is_some_and(|timestamp| *timestamp <= to + 60)
+	};
+
+	let mut runs: Vec<Range<usize>> = vec![];
+	for (index, candle) in candles.iter().enumerate() {
+		let next = candles
+			.get(index + 1)
+			.map_or(candle.timestamp, |next| next.timestamp);
+		if !brackets(candle.timestamp, next) {
+			continue;
+		}
+
+		let end = usize::min(index + 2, candles.len());
+		match runs.last_mut() {
+			Some(run) if run.end >= index => run.end = end,
+			_ => runs.push(index..end),
+		}
+	}
+
+	runs
+}
+
+// repairs as many of the `missing` timestamps as possible from a single fallback source,
+// returns the timestamps it could not repair
+async fn fix_from_source(
+	fallback: &FallbackSymbol,
+	mut missing: Vec<i64>,
+	options: CandleOptions,
+	fixed_discrepancies: &mut Vec<Datapoint>,
+) -> Vec<i64> {
+	let FallbackSymbol { source, symbol } = fallback;
+	missing.sort();
+	let output_interval = config::current().collection_interval;
+	let mut unfixable = vec![];
+
+	// sources only return the latest candles of an interval, so longer outages are
+	// progressively repaired from larger intervals and interpolated down to the output interval
+	for interval in KrakenInterval::ALL
+		.into_iter()
+		.filter(|interval| {
+			*interval as u16 >= output_interval as u16 && *interval as u16 <= MAX_REPAIR_INTERVAL as u16
+		})
+		.filter(|interval| source.supports(*interval))
+	{
+		if missing.is_empty() {
+			break;
+		}
+
+		if let Some(covered) = TimeDelta::try_minutes(interval as i64 * KRAKEN_MAX_DATAPOINTS as i64) {
+			debug!(
+				interval = interval as u16,
+				"interval covers {:?}",
+				covered.hhmmss()
+			);
+		}
+
+		let fallback_datapoints = match source.fetch_candles(symbol, interval).await {
+			Ok(datapoints) => datapoints,
+			Err(error) => {
+				error!(error = ?error, source = source.name(), interval = interval as u16, "error fetching fallback datapoints");
+				continue;
+			}
+		};
+
+		let Some(last_timestamp) = fallback_datapoints.last().map(|d| d.timestamp) else {
+			continue;
+		};
 
-	debug!("outage was {:?} long", outage_time.hhmmss());
-	if let Some(max_datapoints) = TimeDelta::try_minutes(max_minutes_in_interval as i64) {
 		debug!(
-			"max datapoints in interval is {:?}",
-			max_datapoints.hhmmss()
+			last_timestamp,
+			last_predicted_timestamp = missing.last(),
+			datapoints = fallback_datapoints.len(),
+			source = source.name(),
+			interval = interval as u16,
+			"fetched datapoints"
 		);
+
+		let interpolated: HashMap<i64, Datapoint> = bracketing_runs(&fallback_datapoints, &missing)
+			.into_iter()
+			.flat_map(|run| {
+				interpolate_candles(
+					&fallback_datapoints[run],
+					source.provenance(),
+					&interval,
+					&output_interval,
+					options,
+				)
+			})
+			.map(|d| (round_to_minute(d.timestamp), d))
+			.collect();
+
+		missing.retain(|timestamp| {
+			let Some(datapoint) = interpolated.get(&round_to_minute(*timestamp)) else {
+				return true;
+			};
+
+			fixed_discrepancies.push(Datapoint {
+				timestamp: *timestamp,
+				..datapoint.clone()
+			});
+			false
+		});
+
+		// no interval of this source has candles newer than the latest one
+		let (newer, remaining): (Vec<i64>, Vec<i64>) = missing
+			.into_iter()
+			.partition(|timestamp| *timestamp > last_timestamp);
+		if !newer.is_empty() {
+			warn!(
+				count = newer.len(),
+				source = source.name(),
+				"discrepancies are newer than the latest fallback datapoint"
+			);
+		}
+		unfixable.extend(newer);
+		missing = remaining;
 	}
 
-	if outage_time_minutes as u32 >= max_minutes_in_interval as u32 {
-		warn!("all discrepancies will not be able to be fixed",);
-		// @TODO use larger intervals if app goes out for longer than max_minues_in_interval
-		let _new_interval = KrakenInterval::FiveMinutes;
+	missing.extend(unfixable);
+	missing.sort();
+	missing
+}
+
+/// repairs missing timestamps from the fallback sources of `config`, in order,
+/// every source gets the timestamps the sources before it could not repair
+pub async fn fix_discrepancies(
+	config: &PairConfig,
+	datapoints: Vec<i64>,
+	options: CandleOptions,
+) -> Result<Vec<Datapoint>> {
+	if datapoints.len() < 1 {
+		return Err(eyre!("no discrepancies"));
 	}
 
-	let fallback_datapoints = fetch_kraken_datapoints(pair, &interval).await?;
+	if config.fallbacks.is_empty() {
+		return Err(eyre!("no fallback sources configured"));
+	}
 
-	let last_datapoint = fallback_datapoints.last();
-	let fallback_datapoints: HashMap<i64, KrakenDatapoint> = fallback_datapoints
-		.clone()
-		.into_iter()
-		.map(|d| (d.timestamp, d))
-		.collect();
+	let first_missing = *datapoints.iter().min().ok_or_eyre("no discrepancies")?;
+	let outage_time = TimeDelta::try_seconds(Utc::now().timestamp() - first_missing)
+		.ok_or_eyre("outage time calcuation overflowed")?;
+	debug!("outage was {:?} long", outage_time.hhmmss());
 
-	debug!(
-		last_timestamp = (*last_datapoint.unwrap()).timestamp,
-		last_predicted_timestamp = (*datapoints.last().unwrap()),
-		datapoints = fallback_datapoints.len(),
-		interval = interval as u16,
-		"fetched datapoints"
-	);
+	let mut missing = datapoints;
+	let mut fixed_discrepancies: Vec<Datapoint> = vec![];
+
+	for fallback in config.fallbacks.iter() {
+		if missing.is_empty() {
+			break;
+		}
+
+		let count = missing.len();
+		missing = fix_from_source(fallback, missing, options, &mut fixed_discrepancies).await;
+		info!(
+			fallback = ?fallback,
+			fixed = count - missing.len(),
+			remaining = missing.len(),
+			"repaired from fallback source"
+		);
+	}
 
-	let fixed_discrepancies: Vec<Datapoint> = datapoints
-		.iter()
-		.map(|d| {
-			Datapoint::new(
-				match fallback_datapoints.get(d) {
-					Some(fallback_datapoint) => fallback_datapoint.close as f64,
-					_ => return None,
-				},
-				TimeType::Timestamp(d.clone()),
-			)
-			.ok()
-		})
-		.filter_map(|d| d)
-		.collect();
+	if !missing.is_empty() {
+		warn!(
+			count = missing.len(),
+			"all discrepancies will not be able to be fixed"
+		);
+	}
 
+	fixed_discrepancies.sort_by_key(|d| d.timestamp);
 	Ok(fixed_discrepancies)
 }
 
-pub async fn initialize_datapoints(client: &redis::Client, pair: &Pair) -> Result<Vec<Datapoint>> {
-	let into_datapoint = |datapoint: &KrakenDatapoint| -> Result<Datapoint> {
-		Ok(Datapoint::new(
-			datapoint.close as f64,
-			TimeType::Timestamp(datapoint.timestamp),
-		)?)
-	};
-
-	let mut connection = client.get_connection()?;
-	debug!("redis connection established");
-
-	// @TODO please make sure this is sorted
-	let intervals = [
-		KrakenInterval::Day,
-		KrakenInterval::FourHours,
-		KrakenInterval::Hour,
-		KrakenInterval::HalfHour,
-		KrakenInterval::FifteenMinutes,
-		KrakenInterval::default(),
-	];
+/// history of a pair without stored prices, from the kraken candles of every interval down to the
+/// collection interval
+pub async fn initialize_datapoints(
+	config: &PairConfig,
+	options: CandleOptions,
+) -> Result<Vec<Datapoint>> {
+	let symbol = config
+		.fallback_symbol(KRAKEN.name())
+		.ok_or_eyre("no kraken fallback symbol")?;
+
+	// largest first, down to the collection interval
+	let collection_interval = config::current().collection_interval;
+	let intervals: Vec<KrakenInterval> = KrakenInterval::ALL
+		.into_iter()
+		.rev()
+		.filter(|interval| {
+			*interval as u16 > collection_interval as u16
+				&& *interval as u16 <= KrakenInterval::Day as u16
+		})
+		.chain([collection_interval])
+		.collect();
 
 	let smallest_interval = intervals[intervals.len() - 1];
 
@@ -195,7 +447,7 @@ pub async fn initialize_datapoints(client: &redis::Client, pair: &Pair) -> Resul
 	rolled_intervals[intervals.len() - 1] = None;
 
 	let fallback_datapoints: Vec<Vec<Datapoint>> =
-		futures::future::join_all(intervals.into_iter().zip(rolled_intervals).map(
+		futures::future::join_all(intervals.iter().copied().zip(rolled_intervals).map(
 			|(interval, previous_interval)| async move {
 				match previous_interval {
 					Some(previous_interval) => warn!(
@@ -206,21 +458,19 @@ pub async fn initialize_datapoints(client: &redis::Client, pair: &Pair) -> Resul
 					),
 					None => warn!(interval = interval as usize, "no previous interval"),
 				}
-				let mut truncated_datapoints = fetch_kraken_datapoints(pair, &interval).await?;
+				let mut truncated_datapoints = fetch_kraken_candles(symbol, &interval).await?;
 				if let Some(previous_interval) = previous_interval {
 					let interval = interval as usize * 720;
 					let previous_interval = *previous_interval as usize * 720;
 					truncated_datapoints.truncate(((interval * 720) - (previous_interval * 720)) / interval)
 				}
 
-				Ok(interpolate_datapoints(
-					truncated_datapoints
-						.iter()
-						.map(into_datapoint)
-						.filter_map(|x| x.ok())
-						.collect(),
+				Ok(interpolate_candles(
+					&truncated_datapoints,
+					Provenance::Kraken,
 					&interval,
 					&smallest_interval,
+					options,
 				))
 			},
 		))
@@ -229,16 +479,100 @@ pub async fn initialize_datapoints(client: &redis::Client, pair: &Pair) -> Resul
 		.filter_map(|x: Result<Vec<Datapoint>>| x.ok())
 		.collect();
 
-	let first_timestamp = connection
-		.lindex(format!("{}:timestamps", pair.to_string()), -1)
-		.unwrap_or(i64::MIN);
-
-	let mut selected_datapoints: Vec<Datapoint> = fallback_datapoints
-		.concat()
-		.into_iter()
-		.filter(|datapoint| datapoint.timestamp > first_timestamp)
-		.collect();
+	let mut selected_datapoints = fallback_datapoints.concat();
 	selected_datapoints.sort_by_key(|x| x.timestamp);
 
 	Ok(selected_datapoints)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{bracketing_runs, missing_timestamps};
+	use crate::datapoint::KrakenDatapoint;
+	use crate::testing::{candle, START};
+
+	fn series(interval_secs: i64, slots: &[i64]) -> Vec<i64> {
+		slots
+			.iter()
+			.map(|slot| START + slot * interval_secs)
+			.collect()
+	}
+
+	#[test]
+	fn no_gaps() {
+		for minutes in [1, 5, 15] {
+			let interval_secs = minutes * 60;
+			let timestamps = series(interval_secs, &[0, 1, 2, 3]);
+
+			// the interval now falls into is not expected yet
+			let now = START + 4 * interval_secs + 10;
+			assert!(missing_timestamps(&timestamps, now, interval_secs).is_empty());
+		}
+	}
+
+	#[test]
+	fn interior_gaps() {
+		for minutes in [1, 5, 15] {
+			let interval_secs = minutes * 60;
+			let timestamps = series(interval_secs, &[0, 1, 4, 5, 7]);
+
+			let now = START + 8 * interval_secs;
+			assert_eq!(
+				missing_timestamps(&timestamps, now, interval_secs),
+				series(interval_secs, &[2, 3, 6])
+			);
+		}
+	}
+
+	#[test]
+	fn tail_gap_does_not_reach_into_the_future() {
+		for minutes in [1, 5, 15] {
+			let interval_secs = minutes * 60;
+			let timestamps = series(interval_secs, &[0, 1]);
+
+			// ten intervals and a bit later
+			let now = START + 11 * interval_secs + interval_secs / 2;
+			let missing = missing_timestamps(&timestamps, now, interval_secs);
+
+			assert_eq!(
+				missing,
+				series(interval_secs, &[2, 3, 4, 5, 6, 7, 8, 9, 10])
+			);
+			assert!(missing.iter().all(|timestamp| *timestamp < now));
+		}
+	}
+
+	#[test]
+	fn unaligned_timestamps_fill_their_interval() {
+		for minutes in [1, 5, 15] {
+			let interval_secs = minutes * 60;
+			// collected a little after each boundary
+			let timestamps: Vec<i64> = series(interval_secs, &[0, 1, 3])
+				.into_iter()
+				.map(|timestamp| timestamp + 30)
+				.collect();
+
+			let now = START + 4 * interval_secs + 30;
+			assert_eq!(
+				missing_timestamps(&timestamps, now, interval_secs),
+				series(interval_secs, &[2])
+			);
+		}
+	}
+
+	#[test]
+	fn only_candles_around_missing_timestamps_are_interpolated() {
+		let hour = 60 * 60;
+		let candles: Vec<KrakenDatapoint> = (0..8).map(|hours| candle(hours, 1.0, 1.0, 1.0)).collect();
+
+		// inside the second candle, inside the second and third last and on the last one
+		let missing = vec![
+			START + hour + 600,
+			START + 5 * hour + 600,
+			START + 6 * hour + 600,
+			START + 7 * hour,
+		];
+		assert_eq!(bracketing_runs(&candles, &missing), vec![1..3, 5..8]);
+		assert!(bracketing_runs(&candles, &[]).is_empty());
+	}
+}
@@ -1,51 +1,420 @@
-use eyre::Result;
+use std::f64::consts::PI;
+use std::str::FromStr;
 
-use crate::datapoint::{Datapoint, KrakenInterval, TimeType};
+use eyre::{eyre, Report, Result};
+
+use crate::datapoint::{
+	Datapoint, KrakenDatapoint, KrakenInterval, PriceField, Provenance, SourceCandle, TimeType,
+};
+
+/// how the gap between the prices of two candles is filled in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+	/// carry the earlier price forward
+	Step,
+	/// straight line between the two prices
+	Linear,
+	/// monotone cubic hermite spline (fritsch-carlson), smooth without overshooting the prices
+	MonotoneCubic,
+	/// random walk pinned to both prices that touches the high and low of the candle and never
+	/// leaves them, seeded by the candle timestamp so repairs are reproducible
+	BrownianBridge,
+}
+
+impl Default for Interpolation {
+	fn default() -> Self {
+		Interpolation::Linear
+	}
+}
+
+impl FromStr for Interpolation {
+	type Err = Report;
+
+	fn from_str(interpolation: &str) -> Result<Self> {
+		match interpolation {
+			"step" => Ok(Interpolation::Step),
+			"linear" => Ok(Interpolation::Linear),
+			"monotone" => Ok(Interpolation::MonotoneCubic),
+			"bridge" => Ok(Interpolation::BrownianBridge),
+			_ => Err(eyre!("unknown interpolation {}", interpolation)),
+		}
+	}
+}
+
+/// how the candles of fallback sources are turned into datapoints
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CandleOptions {
+	pub price_field: PriceField,
+	pub interpolation: Interpolation,
+}
 
 fn lerp(a: f64, b: f64, amount: f64) -> f64 {
 	(1.0 - amount) * a + amount * b
 }
 
-pub fn interpolate_datapoints(
-	mut datapoints: Vec<Datapoint>,
+// tangents of a monotone cubic spline through evenly spaced prices
+fn monotone_tangents(prices: &[f64]) -> Vec<f64> {
+	if prices.len() < 2 {
+		return vec![0.0; prices.len()];
+	}
+
+	let secants: Vec<f64> = prices
+		.windows(2)
+		.map(|window| window[1] - window[0])
+		.collect();
+
+	let last = prices.len() - 1;
+	let mut tangents: Vec<f64> = (0..prices.len())
+		.map(|index| match index {
+			0 => secants[0],
+			index if index == last => secants[last - 1],
+			// local extrema stay flat so the spline does not overshoot them
+			index if secants[index - 1] * secants[index] <= 0.0 => 0.0,
+			index => (secants[index - 1] + secants[index]) / 2.0,
+		})
+		.collect();
+
+	for (index, secant) in secants.iter().enumerate() {
+		if *secant == 0.0 {
+			tangents[index] = 0.0;
+			tangents[index + 1] = 0.0;
+			continue;
+		}
+
+		let (a, b) = (tangents[index] / secant, tangents[index + 1] / secant);
+		let norm = a * a + b * b;
+		if norm > 9.0 {
+			let tau = 3.0 / norm.sqrt();
+			tangents[index] = tau * a * secant;
+			tangents[index + 1] = tau * b * secant;
+		}
+	}
+
+	tangents
+}
+
+fn hermite(start: f64, end: f64, start_tangent: f64, end_tangent: f64, amount: f64) -> f64 {
+	let (t2, t3) = (amount * amount, amount * amount * amount);
+
+	(2.0 * t3 - 3.0 * t2 + 1.0) * start
+		+ (t3 - 2.0 * t2 + amount) * start_tangent
+		+ (-2.0 * t3 + 3.0 * t2) * end
+		+ (t3 - t2) * end_tangent
+}
+
+// xorshift64, good enough to shake a bridge and needs no extra dependency
+struct Noise(u64);
+
+impl Noise {
+	fn new(seed: i64) -> Self {
+		// xorshift gets stuck on zero
+		Self(seed as u64 ^ 0x9e37_79b9_7f4a_7c15)
+	}
+
+	fn uniform(&mut self) -> f64 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 7;
+		self.0 ^= self.0 << 17;
+
+		(self.0 >> 11) as f64 / (1_u64 << 53) as f64
+	}
+
+	// box-muller
+	fn normal(&mut self) -> f64 {
+		let (u1, u2) = (self.uniform().max(f64::MIN_POSITIVE), self.uniform());
+		(-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+	}
+}
+
+// `steps - 1` prices between `start` and `end` during `candle`, visiting the extreme closest to
+// `start` first and the other one after, with brownian noise pinned to zero at every visit
+fn bridge(start: f64, end: f64, candle: &KrakenDatapoint, steps: usize) -> Vec<f64> {
+	let high = (candle.high as f64).max(start).max(end);
+	let low = (candle.low as f64).min(start).min(end);
+
+	let (first, second) = match high - start < start - low {
+		true => (high, low),
+		false => (low, high),
+	};
+
+	// (step, price) the path has to go through, too few steps can't visit both extremes
+	let first_step = usize::max(1, steps / 3);
+	let second_step = usize::max(first_step + 1, 2 * steps / 3);
+	let mut knots = vec![(0, start)];
+	knots.extend(
+		[(first_step, first), (second_step, second)]
+			.into_iter()
+			.filter(|(step, _)| *step < steps),
+	);
+	knots.push((steps, end));
+
+	let mut noise = Noise::new(candle.timestamp);
+	let volatility = (high - low) / (2.0 * (steps as f64).sqrt());
+
+	knots
+		.windows(2)
+		.flat_map(|window| {
+			let ((start_step, start), (end_step, end)) = (window[0], window[1]);
+			let length = end_step - start_step;
+
+			let walk: Vec<f64> = (0..length)
+				.scan(0.0, |position, _| {
+					*position += volatility * noise.normal();
+					Some(*position)
+				})
+				.collect();
+			let end_position = walk[length - 1];
+
+			(1..=length)
+				.map(|step| {
+					let amount = step as f64 / length as f64;
+					let price = lerp(start, end, amount) + walk[step - 1] - amount * end_position;
+
+					price.clamp(low, high)
+				})
+				.collect::<Vec<f64>>()
+		})
+		// the end belongs to the next datapoint
+		.take(steps - 1)
+		.collect()
+}
+
+/// turns `candles` (sorted oldest first) into datapoints of `provenance`, keeping each candle with
+/// its datapoint, and fills the gaps down to `output_interval` with interpolated datapoints
+pub fn interpolate_candles(
+	candles: &[KrakenDatapoint],
+	provenance: Provenance,
 	input_interval: &KrakenInterval,
 	output_interval: &KrakenInterval,
+	options: CandleOptions,
 ) -> Vec<Datapoint> {
 	let input_interval = (*input_interval) as u16;
 	let output_interval = (*output_interval) as u16;
-	let steps = input_interval / output_interval;
+	let steps = usize::max(1, (input_interval / output_interval) as usize);
+	let CandleOptions {
+		price_field,
+		interpolation,
+	} = options;
 
-	let mut rotated_datapoints = datapoints.clone();
-	rotated_datapoints.rotate_left(1);
-	datapoints.pop();
-	rotated_datapoints.pop();
+	let prices: Vec<f64> = candles
+		.iter()
+		.map(|candle| candle.price(price_field))
+		.collect();
+	let tangents = match interpolation {
+		Interpolation::MonotoneCubic => monotone_tangents(&prices),
+		_ => vec![],
+	};
 
-	datapoints
-		.into_iter()
-		.zip(rotated_datapoints.into_iter())
-		.map(|(datapoint, next_datapoint)| {
-			let timestamp = datapoint.timestamp;
-			let next_timestamp = next_datapoint.timestamp;
+	let into_datapoint = |price: f64, timestamp: i64, provenance: Provenance| {
+		Datapoint::new(price, TimeType::Timestamp(timestamp))
+			.ok()
+			.map(|datapoint| datapoint.with_provenance(provenance))
+	};
 
-			let mut output = vec![datapoint.clone()];
+	candles
+		.iter()
+		.enumerate()
+		.flat_map(|(index, candle)| {
+			let mut output: Vec<Datapoint> = into_datapoint(prices[index], candle.timestamp, provenance)
+				.map(|datapoint| {
+					datapoint.with_candle(Some(SourceCandle {
+						interval: input_interval,
+						candle: *candle,
+					}))
+				})
+				.into_iter()
+				.collect();
 
-			for i in 1..steps {
-				let lerp_amount = (i as f32 / steps as f32) as f64;
+			let Some(next_candle) = candles.get(index + 1) else {
+				return output;
+			};
 
-				let interpolated_price = lerp(datapoint.price, next_datapoint.price, lerp_amount);
-				let interpolated_timestamp = lerp(timestamp as f64, next_timestamp as f64, lerp_amount);
+			let (start, end) = (prices[index], prices[index + 1]);
+			let prices: Vec<f64> = match interpolation {
+				Interpolation::Step => vec![start; steps - 1],
+				Interpolation::Linear => (1..steps)
+					.map(|step| lerp(start, end, step as f64 / steps as f64))
+					.collect(),
+				Interpolation::MonotoneCubic => (1..steps)
+					.map(|step| {
+						hermite(
+							start,
+							end,
+							tangents[index],
+							tangents[index + 1],
+							step as f64 / steps as f64,
+						)
+					})
+					.collect(),
+				// the move from one candle to the next happened during the next candle
+				Interpolation::BrownianBridge => bridge(start, end, next_candle, steps),
+			};
 
-				output.push(Datapoint::new(
-					interpolated_price,
-					TimeType::Timestamp(interpolated_timestamp as i64),
-				)?);
-			}
+			output.extend(prices.into_iter().enumerate().filter_map(|(step, price)| {
+				let timestamp = lerp(
+					candle.timestamp as f64,
+					next_candle.timestamp as f64,
+					(step + 1) as f64 / steps as f64,
+				);
 
-			output.push(next_datapoint);
+				into_datapoint(price, timestamp as i64, Provenance::Interpolated)
+			}));
 
-			Ok(output)
+			output
 		})
-		.filter_map(|x: Result<Vec<Datapoint>>| x.ok())
-		.flatten()
 		.collect()
 }
+
+#[cfg(test)]
+mod tests {
+	use super::{bridge, interpolate_candles, CandleOptions, Interpolation};
+	use crate::datapoint::{Datapoint, KrakenDatapoint, KrakenInterval, PriceField, Provenance};
+	use crate::testing::candle;
+
+	// hourly candles filled in down to five minutes
+	fn interpolate(candles: &[KrakenDatapoint], interpolation: Interpolation) -> Vec<Datapoint> {
+		interpolate_candles(
+			candles,
+			Provenance::Kraken,
+			&KrakenInterval::Hour,
+			&KrakenInterval::FiveMinutes,
+			CandleOptions {
+				price_field: PriceField::Close,
+				interpolation,
+			},
+		)
+	}
+
+	// interpolated prices between each candle and the next one, with the prices of both
+	fn segments(datapoints: &[Datapoint]) -> Vec<(f64, f64, Vec<f64>)> {
+		let candles: Vec<usize> = (0..datapoints.len())
+			.filter(|index| !datapoints[*index].provenance.is_synthetic())
+			.collect();
+
+		candles
+			.windows(2)
+			.map(|window| {
+				let (start, end) = (window[0], window[1]);
+				(
+					datapoints[start].price,
+					datapoints[end].price,
+					datapoints[start + 1..end]
+						.iter()
+						.map(|datapoint| datapoint.price)
+						.collect(),
+				)
+			})
+			.collect()
+	}
+
+	#[test]
+	fn step_carries_the_price_forward() {
+		let candles = [
+			candle(0, 100.0, 100.0, 100.0),
+			candle(1, 110.0, 110.0, 100.0),
+		];
+		let datapoints = interpolate(&candles, Interpolation::Step);
+
+		assert_eq!(datapoints.len(), 13);
+		for (start, _, prices) in segments(&datapoints) {
+			assert_eq!(prices.len(), 11);
+			assert!(prices.iter().all(|price| *price == start));
+		}
+	}
+
+	#[test]
+	fn monotone_cubic_never_overshoots() {
+		let closes = [100.0, 120.0, 121.0, 80.0, 80.0, 90.0, 150.0];
+		let candles: Vec<KrakenDatapoint> = closes
+			.iter()
+			.enumerate()
+			.map(|(hour, close)| candle(hour as i64, *close, *close, *close))
+			.collect();
+		let datapoints = interpolate(&candles, Interpolation::MonotoneCubic);
+
+		assert_eq!(datapoints.len(), (closes.len() - 1) * 12 + 1);
+		for (start, end, prices) in segments(&datapoints) {
+			let (low, high) = (start.min(end), start.max(end));
+			assert!(prices
+				.iter()
+				.all(|price| *price >= low - 1e-9 && *price <= high + 1e-9));
+		}
+	}
+
+	#[test]
+	fn bridge_stays_within_the_candle_and_hits_its_extremes() {
+		let (start, end) = (100.0, 104.0);
+		let next = candle(1, end as f32, 110.0, 95.0);
+
+		for steps in [3, 12, 60] {
+			let prices = bridge(start, end, &next, steps);
+
+			assert_eq!(prices.len(), steps - 1);
+			assert!(prices.iter().all(|price| (95.0..=110.0).contains(price)));
+			assert!(prices.contains(&110.0));
+			assert!(prices.contains(&95.0));
+		}
+	}
+
+	#[test]
+	fn bridge_keeps_the_candle_prices() {
+		let candles = [
+			candle(0, 100.0, 101.0, 99.0),
+			candle(1, 104.0, 110.0, 95.0),
+			candle(2, 102.0, 106.0, 98.0),
+		];
+		let datapoints = interpolate(&candles, Interpolation::BrownianBridge);
+
+		let kept: Vec<(i64, f64)> = datapoints
+			.iter()
+			.filter(|datapoint| datapoint.provenance == Provenance::Kraken)
+			.map(|datapoint| (datapoint.timestamp, datapoint.price))
+			.collect();
+		assert_eq!(
+			kept,
+			candles
+				.iter()
+				.map(|candle| (candle.timestamp, candle.close as f64))
+				.collect::<Vec<(i64, f64)>>()
+		);
+
+		// the same candles give the same path
+		let again = interpolate(&candles, Interpolation::BrownianBridge);
+		assert!(datapoints
+			.iter()
+			.zip(again.iter())
+			.all(|(a, b)| a.price == b.price));
+	}
+
+	#[test]
+	fn single_step_has_no_interpolated_points() {
+		let candles = [
+			candle(0, 100.0, 101.0, 99.0),
+			candle(1, 104.0, 110.0, 95.0),
+			candle(2, 102.0, 106.0, 98.0),
+		];
+
+		for interpolation in [
+			Interpolation::Step,
+			Interpolation::Linear,
+			Interpolation::MonotoneCubic,
+			Interpolation::BrownianBridge,
+		] {
+			let datapoints = interpolate_candles(
+				&candles,
+				Provenance::Kraken,
+				&KrakenInterval::Hour,
+				&KrakenInterval::Hour,
+				CandleOptions {
+					price_field: PriceField::Close,
+					interpolation,
+				},
+			);
+
+			assert_eq!(datapoints.len(), candles.len());
+			assert!(datapoints
+				.iter()
+				.all(|datapoint| !datapoint.provenance.is_synthetic()));
+		}
+	}
+}
@@ -1,10 +1,23 @@
 mod api;
+mod backfill;
+mod chainlink;
+mod config;
 mod datapoint;
+mod fallback;
 mod fixes;
 mod interpolate;
+mod outlier;
 mod price;
+mod rpc;
+mod source;
+mod store;
+mod swap;
+#[cfg(test)]
+mod testing;
 
+use std::collections::HashMap;
 use std::env::{self, VarError};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::time::Duration;
 
 use actix_web::web::Data;
@@ -15,28 +28,36 @@ use datapoint::Datapoint;
 use ethers::prelude::*;
 use eyre::Result;
 use fixes::{find_discrepancies, fix_discrepancies, initialize_datapoints};
+use futures::future;
 use lazy_static::lazy_static;
-use redis::Client;
-use shared::{coin::Pair, CustomInterval};
+use shared::CustomInterval;
+use tokio::task::JoinHandle;
 use tracing::{debug, error, info, level_filters::LevelFilter, warn, Level};
 use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
 use tracing_panic::panic_hook;
 use tracing_subscriber::{layer::SubscriberExt, Layer, Registry};
 
-use crate::datapoint::TimeType;
-use crate::price::{fetch_prices, store_prices};
+use crate::backfill::backfill_datapoints;
+use crate::config::{ChainConfig, Config, PairConfig, StoreConfig};
+use crate::datapoint::{Provenance, TimeType};
+use crate::interpolate::CandleOptions;
+use crate::outlier::QUARANTINE_SOURCE;
+use crate::price::fetch_block;
+use crate::rpc::{FailoverClient, RpcClients};
+use crate::source::{
+	aggregate_prices, AggregatedPrice, ChainlinkSource, KrakenSource, PriceSource, QuoterSource,
+};
+use crate::store::{ChainStores, MemoryStore, PriceStore, RedisStore, SqliteStore};
+use crate::swap::{resolve_pools, stream_swaps, VolumeTracker};
 
-// have to use Duration::milliseconds due to milliseconds (and micro/nanoseconds)
-// being the only way to construct a chrono::Duration in a const
-pub const COLLECTION_INTERVAL: CustomInterval =
-	CustomInterval(Duration::from_millis(5 * 60 * 1_000));
-
-pub const CURRENT_CHAIN: Chain = Chain::Mainnet;
+// read from the config file, so only usable after Config::init
 lazy_static! {
-	static ref SUPPORTED_PAIRS: Vec<Pair> = vec![Pair::usdc_weth(Some(CURRENT_CHAIN as u64))];
+	pub static ref COLLECTION_INTERVAL: CustomInterval = CustomInterval(Duration::from_secs(
+		config::current().collection_interval as u64 * 60
+	));
 }
 
-async fn notify_transaction_processor(timestamp: i64) {
+async fn notify_transaction_processor(timestamp: i64, chain: Chain) {
 	let transaction_processor_uri = match env::var("TRANSACTION_PROCESSOR_URI") {
 		Ok(uri) => uri,
 		Err(error) => {
@@ -48,8 +69,8 @@ async fn notify_transaction_processor(timestamp: i64) {
 	};
 
 	match reqwest::get(format!(
-		"{}/price_update?timestamp={}",
-		transaction_processor_uri, timestamp
+		"{}/price_update?timestamp={}&chain={}",
+		transaction_processor_uri, timestamp, chain
 	))
 	.await
 	{
@@ -60,11 +81,39 @@ async fn notify_transaction_processor(timestamp: i64) {
 	}
 }
 
-async fn collect_prices<P>(provider: Provider<P>, client: Client)
-where
+async fn collect_prices<P>(
+	provider: Provider<P>,
+	chain: &ChainConfig,
+	sources: Arc<Vec<Box<dyn PriceSource>>>,
+	volume_tracker: Arc<VolumeTracker>,
+	store: Arc<dyn PriceStore>,
+) where
 	P: JsonRpcClient + 'static,
 {
-	let prices = fetch_prices(provider, &SUPPORTED_PAIRS).await;
+	// pin every on-chain read to the same block so the collection can be reproduced
+	let block = match fetch_block(&provider).await {
+		Ok(block) => Some(block),
+		Err(error) => {
+			warn!(error = ?error, "error fetching latest block, prices will not be pinned");
+			None
+		}
+	};
+	let block_id = block.map_or(BlockNumber::Latest.into(), |block| block.number.into());
+
+	// swaps up to the pinned block, the ones after it are counted by the next collection, as are
+	// the ones of prices that are not stored
+	let volumes = match block {
+		Some(block) => volume_tracker
+			.volumes(&provider, block.number)
+			.await
+			.unwrap_or_else(|error| {
+				warn!(error = ?error, "error reading swap volumes, prices will have no volume");
+				HashMap::new()
+			}),
+		None => HashMap::new(),
+	};
+
+	let prices = aggregate_prices(&sources, &chain.pairs, block_id).await;
 	let duration =
 		TimeDelta::try_minutes(1).expect("1 minute did not convert into timedelta properly");
 
@@ -72,19 +121,192 @@ where
 		.duration_round(duration)
 		.expect("price collection timestamp did not round properly");
 
-	for (pair, price) in prices {
+	let outlier_filter = &config::current().outlier;
+	let mut stored = false;
+	for aggregated in prices {
+		let provenance = aggregated.provenance();
+		let AggregatedPrice {
+			pair,
+			price,
+			sources,
+		} = aggregated;
+
+		// keep every source price around so the median can be audited
+		for source_price in sources.iter() {
+			let result =
+				Datapoint::new(source_price.price, TimeType::DateTime(datetime)).and_then(|datapoint| {
+					let datapoint = datapoint
+						.with_block(block.filter(|_| source_price.on_chain()))
+						.with_provenance(source_price.provenance)
+						.with_fees(source_price.fees.clone());
+					store.store_source_prices(&pair, source_price.source, vec![datapoint])
+				});
+
+			if let Err(error) = result {
+				error!(error = ?error, source = source_price.source, "error storing source price");
+			}
+		}
+
 		let datapoint = match Datapoint::new(price, TimeType::DateTime(datetime)) {
-			Ok(datapoint) => datapoint,
+			Ok(datapoint) => datapoint
+				.with_block(block.filter(|_| provenance == Provenance::OnChain))
+				.with_provenance(provenance)
+				.with_volume(volumes.get(&pair.to_string()).copied()),
 			Err(error) => return error!(error = ?error, "error creating datapoint"),
 		};
 
-		match store_prices(&client, &pair, vec![datapoint]) {
-			Ok(_) => debug!(price, pair = ?pair, "stored price"),
+		let deviation = match outlier_filter.deviation(
+			store.as_ref(),
+			&pair,
+			price,
+			datetime.timestamp(),
+			&sources,
+		) {
+			Ok(deviation) => deviation,
+			Err(error) => return error!(error = ?error, "error checking price for outliers"),
+		};
+
+		// suspicious prices are kept aside instead of being published
+		if let Some(deviation) = deviation.filter(|deviation| outlier_filter.is_outlier(*deviation)) {
+			error!(
+				price,
+				deviation,
+				max_deviation = outlier_filter.max_deviation,
+				pair = ?pair,
+				"price deviates too much, quarantining"
+			);
+
+			if let Err(error) = store.store_source_prices(&pair, QUARANTINE_SOURCE, vec![datapoint]) {
+				error!(error = ?error, "error quarantining price");
+			}
+
+			continue;
+		}
+
+		let volume = datapoint.volume;
+		match store.store_prices(&pair, vec![datapoint]) {
+			Ok(0) => warn!(price, pair = ?pair, "a price is already stored at this timestamp, skipping"),
+			Ok(_) => {
+				if let Some(volume) = volume {
+					volume_tracker.commit(&pair, &volume);
+				}
+
+				stored = true;
+				debug!(price, pair = ?pair, "stored price")
+			}
 			Err(error) => return error!(error = ?error, "error storing prices"),
 		}
 	}
 
-	notify_transaction_processor(datetime.timestamp()).await;
+	if !stored {
+		return warn!("no prices were stored, not notifying transaction processor");
+	}
+
+	notify_transaction_processor(datetime.timestamp(), chain.chain).await;
+}
+
+// store the prices of `chain` go into, chains share the sqlite database and redis server
+fn open_store(config: &StoreConfig, chain: &ChainConfig) -> Result<Arc<dyn PriceStore>> {
+	let pairs = chain.pairs.iter().map(|pair| &pair.pair);
+
+	Ok(match config {
+		StoreConfig::Sqlite(sqlite_path) => Arc::new(SqliteStore::open(sqlite_path, chain.chain)?),
+		StoreConfig::Redis(redis_uri) => Arc::new(RedisStore::open(
+			redis::Client::open(redis_uri.as_str())?,
+			chain.chain,
+			pairs,
+		)?),
+		StoreConfig::Memory => {
+			warn!(chain = %chain.chain, "prices will only be kept in memory");
+			Arc::new(MemoryStore::new())
+		}
+	})
+}
+
+#[derive(Default, Debug)]
+struct RepairSummary {
+	/// timestamps missing from the stored series
+	missing: usize,
+	/// datapoints that could be recovered and were inserted
+	fixed: usize,
+}
+
+async fn repair_pair<P>(
+	provider: &Provider<P>,
+	sources: &[Box<dyn PriceSource>],
+	store: &dyn PriceStore,
+	config: &PairConfig,
+	archive_backfill: bool,
+	candle_options: CandleOptions,
+) -> Result<RepairSummary>
+where
+	P: JsonRpcClient + 'static,
+{
+	let pair = &config.pair;
+	store.repair(pair)?;
+
+	let discrepancies = find_discrepancies(store, pair)?;
+	if discrepancies.is_empty() {
+		return Ok(RepairSummary::default());
+	}
+
+	let missing = discrepancies.len();
+	warn!(pair = ?pair, count = missing, "fixing discrepancies");
+
+	let datapoints = match archive_backfill {
+		true => backfill_datapoints(provider, sources, config, discrepancies).await?,
+		false => fix_discrepancies(config, discrepancies, candle_options).await?,
+	};
+
+	let fixed = datapoints.len();
+	store.insert_prices(pair, datapoints)?;
+
+	Ok(RepairSummary { missing, fixed })
+}
+
+/// finds and fixes missing datapoints of every pair on `chain`
+async fn heal_prices<P>(
+	provider: Provider<P>,
+	chain: &ChainConfig,
+	sources: Arc<Vec<Box<dyn PriceSource>>>,
+	store: Arc<dyn PriceStore>,
+	archive_backfill: bool,
+	candle_options: CandleOptions,
+) where
+	P: JsonRpcClient + 'static,
+{
+	let mut total = RepairSummary::default();
+
+	for config in chain.pairs.iter() {
+		match repair_pair(
+			&provider,
+			&sources,
+			store.as_ref(),
+			config,
+			archive_backfill,
+			candle_options,
+		)
+		.await
+		{
+			Ok(summary) => {
+				debug!(pair = ?config.pair, missing = summary.missing, fixed = summary.fixed, "repaired pair");
+				total.missing += summary.missing;
+				total.fixed += summary.fixed;
+			}
+			Err(error) => error!(error = ?error, pair = ?config.pair, "error repairing pair"),
+		}
+	}
+
+	match total.missing {
+		0 => info!(chain = %chain.chain, "no discrepancies found"),
+		_ => info!(
+			chain = %chain.chain,
+			missing = total.missing,
+			fixed = total.fixed,
+			unfixed = total.missing.saturating_sub(total.fixed),
+			"repaired discrepancies"
+		),
+	}
 }
 
 #[actix_web::main]
@@ -112,70 +334,160 @@ async fn main() -> Result<()> {
 
 	// transaction processor is optional
 	// env::var("TRANSACTION_PROCESSOR_URI").expect("TRANSACTION_PROCESSOR_URI should be in .env");
-	env::var("QUOTER_ADDRESS").expect("QUOTER_ADDRESS should be in .env");
-	let infura_secret = env::var("INFURA_SECRET").expect("INFURA_SECRET should be in .env");
-	let redis_uri = env::var("REDIS_URI").expect("REDIS_URI should be in .env");
-	let transport_url = format!("https://mainnet.infura.io/v3/{infura_secret}");
+	let config = Config::init()?;
 
-	let web3_provider = Provider::<Http>::try_from(transport_url)?.for_chain(CURRENT_CHAIN);
-	let redis_client = redis::Client::open(redis_uri)?;
+	// every chain gets its own store, the api picks one by the chain parameter
+	let stores: ChainStores = config
+		.chains
+		.iter()
+		.map(|chain| Ok((chain.chain, open_store(&config.store, chain)?)))
+		.collect::<Result<_>>()?;
 
+	let (archive_backfill, candle_options) = (config.archive_backfill, config.candle_options);
+
+	let repair_interval = CustomInterval(config.repair_interval);
+	let mut rpc_clients = RpcClients::new();
 	let mut scheduler = AsyncScheduler::new();
 
-	for pair in (*SUPPORTED_PAIRS).clone().into_iter() {
-		async {
-			match initialize_datapoints(&redis_client, &pair).await {
-				Ok(datapoints) => {
-					if datapoints.len() > 0 {
-						let _ = store_prices(&redis_client, &pair, datapoints);
-					}
-				}
-				Err(error) => return error!(error = ?error, "error getting initial datapoints"),
-			};
+	// chains are started one after the other, their jobs then run side by side
+	for chain in config.chains.iter() {
+		let store = stores[&chain.chain].clone();
+		let rpc_client = FailoverClient::new(&chain.rpc_urls, config.rpc)?;
+		rpc_clients.insert(chain.chain, rpc_client.clone());
+		let mut web3_provider = Provider::new(rpc_client);
+		// what Provider::for_chain does for plain http providers
+		if let Some(blocktime) = chain.chain.average_blocktime_hint() {
+			web3_provider.set_interval(blocktime / 2);
+		}
 
-			let discrepancies = match find_discrepancies(&redis_client, &pair) {
-				Ok(datapoints) => datapoints,
-				Err(error) => return error!(error = ?error, "error finding discrepancies"),
-			};
+		let sources: Arc<Vec<Box<dyn PriceSource>>> = Arc::new(vec![
+			Box::new(QuoterSource::new(web3_provider.clone(), chain.quoter)),
+			Box::new(ChainlinkSource::new(web3_provider.clone())),
+			Box::new(KrakenSource::new()?),
+		]);
 
-			if discrepancies.len() < 1 {
-				return info!("no discrepancies found");
+		for pair_config in chain.pairs.iter() {
+			let pair = &pair_config.pair;
+			if let Err(error) = store.repair(pair) {
+				error!(error = ?error, chain = %chain.chain, "error checking price consistency");
+				continue;
 			}
 
-			warn!(pair = ?pair, count = discrepancies.len(), "fixing discrepancies");
+			// kraken only starts a series, every gap after that, like the downtime before this start,
+			// is left to heal_prices and the configured backfill source
+			match store.last_timestamp(pair) {
+				Ok(None) if !archive_backfill => {}
+				Ok(_) => continue,
+				Err(error) => {
+					error!(error = ?error, chain = %chain.chain, "error getting last stored timestamp");
+					continue;
+				}
+			}
 
-			match fix_discrepancies(&pair, discrepancies).await {
+			match initialize_datapoints(pair_config, candle_options).await {
+				Ok(datapoints) if datapoints.is_empty() => {}
 				Ok(datapoints) => {
-					if let Ok(_) = store_prices(&redis_client, &pair, datapoints) {
-						info!("stored fixed discrepancies");
+					if let Err(error) = store.store_prices(pair, datapoints) {
+						error!(error = ?error, chain = %chain.chain, "error storing initial datapoints");
 					}
 				}
-				Err(error) => error!(error = ?error, "error fixing discrepancies"),
+				Err(error) => {
+					error!(error = ?error, chain = %chain.chain, "error getting initial datapoints")
+				}
 			}
 		}
+
+		// fix whatever went missing while the collector was down before collecting again
+		heal_prices(
+			web3_provider.clone(),
+			chain,
+			sources.clone(),
+			store.clone(),
+			archive_backfill,
+			candle_options,
+		)
 		.await;
-	}
 
-	let redis_client_clone = redis_client.clone();
-	scheduler
-		.every(COLLECTION_INTERVAL.interval())
-		.run(move || {
-			info!(
-				interval = format!("{}s", COLLECTION_INTERVAL.std_duration().as_secs()),
-				"collecting prices"
-			);
+		// chains without a block time hint are taken to make a block every second
+		let blocktime = chain
+			.chain
+			.average_blocktime_hint()
+			.unwrap_or(Duration::from_secs(1));
+		let volume_tracker = Arc::new(VolumeTracker::new(
+			resolve_pools(Arc::new(web3_provider.clone()), chain).await,
+			(COLLECTION_INTERVAL.std_duration().as_millis() / blocktime.as_millis().max(1)) as u64,
+		));
 
-			collect_prices(web3_provider.clone(), redis_client_clone.clone())
+		// every swap in the pools, next to the prices sampled each collection
+		if let Some(ws_url) = &chain.ws_url {
+			rt::spawn(stream_swaps(ws_url.clone(), chain, store.clone()));
+		}
+
+		// a repair can take minutes, so it runs in its own task instead of holding up the scheduler
+		// and with it the collections, and only once at a time
+		let (provider_clone, sources_clone, store_clone) =
+			(web3_provider.clone(), sources.clone(), store.clone());
+		let repair: Arc<Mutex<Option<JoinHandle<()>>>> = Arc::new(Mutex::new(None));
+		scheduler.every(repair_interval.interval()).run(move || {
+			let mut repair = repair.lock().unwrap_or_else(PoisonError::into_inner);
+			match repair.as_ref() {
+				Some(handle) if !handle.is_finished() => warn!(
+					chain = %chain.chain,
+					"previous repair is still running, skipping this one"
+				),
+				_ => {
+					info!(
+						interval = format!("{}s", repair_interval.std_duration().as_secs()),
+						chain = %chain.chain,
+						"repairing prices"
+					);
+
+					*repair = Some(tokio::spawn(heal_prices(
+						provider_clone.clone(),
+						chain,
+						sources_clone.clone(),
+						store_clone.clone(),
+						archive_backfill,
+						candle_options,
+					)));
+				}
+			}
+
+			future::ready(())
 		});
 
-	let redis_client_clone = redis_client.clone();
+		scheduler
+			.every(COLLECTION_INTERVAL.interval())
+			.run(move || {
+				info!(
+					interval = format!("{}s", COLLECTION_INTERVAL.std_duration().as_secs()),
+					chain = %chain.chain,
+					"collecting prices"
+				);
+
+				collect_prices(
+					web3_provider.clone(),
+					chain,
+					sources.clone(),
+					volume_tracker.clone(),
+					store.clone(),
+				)
+			});
+	}
+
+	let (stores, rpc_clients) = (Data::new(stores), Data::new(rpc_clients));
 	let server = HttpServer::new(move || {
 		App::new()
 			.service(api::prices_wrapper)
 			.service(api::current)
-			.app_data(Data::new(redis_client_clone.clone()))
+			.service(api::olhc)
+			.service(api::trades)
+			.service(api::trade_olhc)
+			.service(api::rpc_stats)
+			.app_data(stores.clone())
+			.app_data(rpc_clients.clone())
 	})
-	.bind(("127.0.0.1", 80))?
+	.bind(config.bind_address)?
 	.run();
 
 	rt::spawn(server);
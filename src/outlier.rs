@@ -0,0 +1,92 @@
+use std::str::FromStr;
+
+use eyre::{Report, Result};
+use serde::Deserialize;
+use shared::coin::Pair;
+
+use crate::source::SourcePrice;
+use crate::store::PriceStore;
+use crate::COLLECTION_INTERVAL;
+
+/// name prices rejected by the outlier filter are stored under
+pub const QUARANTINE_SOURCE: &str = "quarantine";
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum OutlierReference {
+	/// compare against the price stored in the previous collection, if there is one.
+	/// a quarantined price leaves a gap, so a real jump is only held back for a single collection
+	Previous,
+	/// compare against the price of a single source from the same tick
+	Source(String),
+}
+
+/// `previous` or the name of a source
+impl FromStr for OutlierReference {
+	type Err = Report;
+
+	fn from_str(reference: &str) -> Result<Self> {
+		match reference {
+			"previous" => Ok(OutlierReference::Previous),
+			source => Ok(OutlierReference::Source(source.to_string())),
+		}
+	}
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields, default)]
+pub struct OutlierFilter {
+	/// largest accepted relative deviation from the reference, 0.05 being 5%
+	pub max_deviation: f64,
+	pub reference: OutlierReference,
+}
+
+impl Default for OutlierFilter {
+	fn default() -> Self {
+		Self::new(0.05, OutlierReference::Previous)
+	}
+}
+
+impl OutlierFilter {
+	pub fn new(max_deviation: f64, reference: OutlierReference) -> Self {
+		Self {
+			max_deviation,
+			reference,
+		}
+	}
+
+	/// relative deviation of `price` collected at `timestamp` from the reference,
+	/// `None` if there is nothing to compare against
+	pub fn deviation(
+		&self,
+		store: &dyn PriceStore,
+		pair: &Pair,
+		price: f64,
+		timestamp: i64,
+		sources: &[SourcePrice],
+	) -> Result<Option<f64>> {
+		let collection_interval_secs = COLLECTION_INTERVAL.std_duration().as_secs() as i64;
+
+		let reference = match &self.reference {
+			OutlierReference::Previous => store
+				.get_datapoints(pair, 1, None)?
+				.last()
+				.filter(|datapoint| timestamp - datapoint.timestamp <= collection_interval_secs)
+				.map(|datapoint| datapoint.price),
+			OutlierReference::Source(name) => sources
+				.iter()
+				.find(|source| source.source == name)
+				.map(|source| source.price),
+		};
+
+		Ok(
+			reference
+				.filter(|reference| *reference != 0.0)
+				.map(|reference| ((price - reference) / reference).abs()),
+		)
+	}
+
+	pub fn is_outlier(&self, deviation: f64) -> bool {
+		deviation > self.max_deviation
+	}
+}
@@ -1,111 +1,268 @@
-use std::{env, ops::Div, sync::Arc};
+use std::sync::Arc;
 
-use chrono::{DateTime, DurationRound, TimeDelta};
 use ethers::prelude::*;
-use ethers::utils::parse_units;
-use eyre::{Context, OptionExt, Result};
+use ethers::utils::{format_units, parse_units};
+use eyre::{eyre, Context, OptionExt, Result};
 use futures::future;
-use redis::Commands;
-use rug::{float::MiniFloat, ops::CompleteRound};
+use serde::Deserialize;
 use shared::{abis::Quoter, coin::Pair};
-use tracing::{debug, error, info, instrument};
+use tracing::{debug, error};
 
-use crate::datapoint::Datapoint;
+use crate::config::PairConfig;
+use crate::datapoint::BlockInfo;
 
-pub async fn fetch_prices<P>(provider: Provider<P>, pairs: &[Pair]) -> Vec<(Pair, f64)>
+#[derive(Deserialize, Copy, Clone, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FeeTier {
+	Lowest = 100,
+	Low = 500,
+	Medium = 3000,
+	High = 10000,
+}
+
+impl FeeTier {
+	pub const ALL: [FeeTier; 4] = [
+		FeeTier::Lowest,
+		FeeTier::Low,
+		FeeTier::Medium,
+		FeeTier::High,
+	];
+}
+
+impl Default for FeeTier {
+	fn default() -> Self {
+		FeeTier::Low
+	}
+}
+
+#[derive(Deserialize, Copy, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum FeeSelection {
+	/// always quote the pool with this fee tier
+	Tier(FeeTier),
+	/// quote every fee tier and keep the pool that gives the best price
+	Best,
+}
+
+impl Default for FeeSelection {
+	fn default() -> Self {
+		FeeSelection::Tier(FeeTier::default())
+	}
+}
+
+impl FeeSelection {
+	pub fn tiers(&self) -> Vec<FeeTier> {
+		match self {
+			FeeSelection::Tier(tier) => vec![*tier],
+			FeeSelection::Best => FeeTier::ALL.to_vec(),
+		}
+	}
+}
+
+/// a single swap in a multi-hop path, into `token` through the pool with `fee`
+#[derive(Deserialize, Copy, Clone, Debug)]
+pub struct Hop {
+	pub token: Address,
+	pub fee: FeeTier,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Route {
+	/// quote the direct pool between the two coins of the pair
+	Single(FeeSelection),
+	/// quote through a path of pools, the last hop has to end in the quote coin of the pair
+	Path(Vec<Hop>),
+}
+
+impl Default for Route {
+	fn default() -> Self {
+		Route::Single(FeeSelection::default())
+	}
+}
+
+#[derive(Clone, Debug)]
+pub struct Quote {
+	pub pair: Pair,
+	pub price: f64,
+	/// fee tier of every pool the quote went through
+	pub fees: Vec<FeeTier>,
+}
+
+// encodes a path the way the quoter expects it: token, 3 byte fee, token, 3 byte fee, ..., token
+fn encode_path(pair: &Pair, hops: &[Hop]) -> Result<Bytes> {
+	let last_hop = hops.last().ok_or_eyre("path has no hops")?;
+	if last_hop.token != pair.0.address {
+		return Err(eyre!("path does not end in {}", pair.0.name));
+	}
+
+	let mut path = pair.1.address.as_bytes().to_vec();
+	for hop in hops {
+		path.extend_from_slice(&(hop.fee as u32).to_be_bytes()[1..]);
+		path.extend_from_slice(hop.token.as_bytes());
+	}
+
+	Ok(path.into())
+}
+
+fn one_unit(pair: &Pair) -> Result<U256> {
+	Ok(U256::from(parse_units(1.0, pair.1.decimals).wrap_err(
+		format!("1 {} did not parse correctly", pair.1.name),
+	)?))
+}
+
+fn into_u128(amount: U256) -> Result<u128> {
+	std::panic::catch_unwind(|| amount.as_u128())
+		.ok()
+		.ok_or_eyre("panic when converting price to u128")
+}
+
+/// latest block, on-chain prices of a collection are all read at this block
+pub async fn fetch_block<P: JsonRpcClient>(provider: &Provider<P>) -> Result<BlockInfo> {
+	let block = provider
+		.get_block(BlockNumber::Latest)
+		.await?
+		.ok_or_eyre("latest block was not found")?;
+
+	Ok(BlockInfo {
+		number: block
+			.number
+			.ok_or_eyre("latest block has no number")?
+			.as_u64(),
+		hash: block.hash.ok_or_eyre("latest block has no hash")?,
+		timestamp: block.timestamp.as_u64() as i64,
+	})
+}
+
+// quote coins can have any number of decimals, so the amount is scaled as a decimal string
+fn to_price(amount: u128, decimals: u32) -> Result<f64> {
+	Ok(format_units(U256::from(amount), decimals)?.parse()?)
+}
+
+async fn quote_tier<M: Middleware + 'static>(
+	quoter: &Quoter<M>,
+	pair: &Pair,
+	fee: FeeTier,
+	block: BlockId,
+) -> Result<u128> {
+	let amount = quoter
+		.quote_exact_input_single(
+			pair.1.address,
+			pair.0.address,
+			fee as u32,
+			one_unit(pair)?,
+			U256::zero(),
+		)
+		.block(block)
+		.call()
+		.await?;
+
+	into_u128(amount)
+}
+
+async fn quote_single<M: Middleware + 'static>(
+	quoter: &Quoter<M>,
+	pair: &Pair,
+	selection: &FeeSelection,
+	block: BlockId,
+) -> Result<(Vec<FeeTier>, u128)> {
+	let tiers = selection.tiers();
+
+	let quotes: Vec<(FeeTier, u128)> = future::join_all(
+		tiers
+			.iter()
+			.map(|fee| quote_tier(quoter, pair, *fee, block)),
+	)
+	.await
+	.into_iter()
+	.zip(tiers.iter())
+	.filter_map(|(amount, fee)| match amount {
+		Ok(amount) => Some((*fee, amount)),
+		Err(error) => {
+			// pools don't exist for every tier, so only a single tier failing is an error
+			if tiers.len() > 1 {
+				debug!(error = ?error, fee = *fee as u32, "no quote for fee tier");
+			} else {
+				error!(error = ?error, fee = *fee as u32, "error getting price");
+			}
+
+			None
+		}
+	})
+	.collect();
+
+	let (fee, amount) = quotes
+		.into_iter()
+		.max_by_key(|(_, amount)| *amount)
+		.ok_or_eyre("no fee tier returned a quote")?;
+
+	Ok((vec![fee], amount))
+}
+
+async fn quote_path<M: Middleware + 'static>(
+	quoter: &Quoter<M>,
+	pair: &Pair,
+	hops: &[Hop],
+	block: BlockId,
+) -> Result<(Vec<FeeTier>, u128)> {
+	let amount = quoter
+		.quote_exact_input(encode_path(pair, hops)?, one_unit(pair)?)
+		.block(block)
+		.call()
+		.await?;
+
+	Ok((hops.iter().map(|hop| hop.fee).collect(), into_u128(amount)?))
+}
+
+/// quotes every pair at `block`
+pub async fn fetch_prices<P>(
+	provider: Provider<P>,
+	quoter: Address,
+	pairs: &[PairConfig],
+	block: BlockId,
+) -> Vec<Quote>
 where
 	P: JsonRpcClient + 'static,
 {
-	let quoter = Arc::new(Quoter::new(
-		env::var("QUOTER_ADDRESS")
-			.expect("QUOTER_ADDRESS should be in .env")
-			.parse::<Address>()
-			.expect("QUOTER_ADDRESS should be a valid address"),
-		provider.into(),
-	));
-
-	let prices: Vec<f64> = future::join_all(pairs.into_iter().map(|pair| {
-		debug!(pair = ?pair, "fetched price");
-		async {
-			let price = quoter
-				.quote_exact_input_single(
-					pair.1.address,
-					pair.0.address,
-					500,
-					U256::from(
-						parse_units(1.0, pair.1.decimals)
-							.wrap_err(format!("1 {} did not parse correctly", pair.1.name))?,
-					),
-					U256::zero(),
-				)
-				.call()
-				.await?;
-			let price = std::panic::catch_unwind(|| price.as_u128())
-				.ok()
-				.ok_or_eyre("panic when converting price to u128")?;
-
-			Ok((pair.clone(), price))
+	let quoter = Arc::new(Quoter::new(quoter, provider.into()));
+
+	future::join_all(pairs.iter().map(|config| {
+		let quoter = quoter.clone();
+		async move {
+			let pair = &config.pair;
+			let (fees, amount) = match &config.route {
+				Route::Single(selection) => quote_single(&quoter, pair, selection, block).await?,
+				Route::Path(hops) => quote_path(&quoter, pair, hops, block).await?,
+			};
+
+			debug!(pair = ?pair, fees = ?fees, "fetched price");
+			Ok(Quote {
+				pair: pair.clone(),
+				price: to_price(amount, pair.0.decimals)?,
+				fees,
+			})
 		}
 	}))
 	.await
 	.into_iter()
-	.filter_map(|x: Result<(Pair, u128)>| {
+	.filter_map(|x: Result<Quote>| {
 		if let Err(ref error) = x {
 			error!(error = ?error, "error getting price");
 		}
 
 		x.ok()
 	})
-	.map(|(pair, price)| {
-		MiniFloat::from(price)
-			.borrow_excl()
-			.div(i32::pow(10, pair.0.decimals))
-			.complete(24)
-			.to_f64()
-	})
-	.collect();
-
-	pairs.to_vec().into_iter().zip(prices.into_iter()).collect()
+	.collect()
 }
 
-#[instrument(err, skip(client, datapoints))]
-pub fn store_prices(client: &redis::Client, pair: &Pair, datapoints: Vec<Datapoint>) -> Result<()> {
-	let mut connection = client.get_connection()?;
-	debug!("redis connection established");
-
-	let count = datapoints.len();
-	let datapoints_iter = datapoints.iter();
+#[cfg(test)]
+mod tests {
+	use super::to_price;
 
-	if let Err(error) = connection.rpush::<String, Vec<String>, i32>(
-		format!("{}:prices", pair.to_string()),
-		datapoints_iter
-			.clone()
-			.map(|datapoint| datapoint.price.to_string())
-			.collect(),
-	) {
-		error!(error = ?error, datapoints = ?datapoints, "error pushing price to redis");
+	#[test]
+	fn scales_by_quote_decimals() {
+		assert_eq!(to_price(2_500_000_000, 6).unwrap(), 2500.0);
+		assert_eq!(to_price(15_000_000_000_000_000_000, 18).unwrap(), 15.0);
+		assert_eq!(to_price(1, 18).unwrap(), 1e-18);
 	}
-
-	let duration =
-		TimeDelta::try_minutes(1).expect("1 minute did not convert into timedelta propperly");
-	if let Err(error) = connection.rpush::<String, Vec<String>, i32>(
-		format!("{}:timestamps", pair.to_string()),
-		datapoints_iter
-			.map(|datapoint| {
-				Some(
-					DateTime::from_timestamp(datapoint.timestamp, 0)?
-						.duration_round(duration)
-						.ok()?
-						.timestamp()
-						.to_string(),
-				)
-			})
-			.filter_map(|x| x)
-			.collect(),
-	) {
-		error!(error = ?error, datapoints = ?datapoints, "error pushing timestamp to redis");
-	}
-
-	info!(count = count, "stored datapoints");
-	Ok(())
 }
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use ethers::providers::{
+	Http, HttpClientError, JsonRpcClient, JsonRpcError, ProviderError, RpcError,
+};
+use ethers::types::Chain;
+use eyre::{eyre, WrapErr};
+use reqwest::Url;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+// json-rpc error code of calls that reverted
+const EXECUTION_REVERTED: i64 = 3;
+
+/// the rpc client of every collected chain
+pub type RpcClients = HashMap<Chain, FailoverClient>;
+
+/// how rpc requests are retried and when endpoints are skipped
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(deny_unknown_fields, default)]
+pub struct RetryPolicy {
+	/// rounds over every endpoint before a request fails
+	pub attempts: u32,
+	/// milliseconds waited before the second round, doubled for every round after
+	pub backoff: u64,
+	/// consecutive failures after which an endpoint is only used if every other endpoint fails too
+	pub failure_threshold: u32,
+	/// seconds an endpoint stays unhealthy for
+	pub cooldown: u64,
+}
+
+impl Default for RetryPolicy {
+	fn default() -> Self {
+		Self {
+			attempts: 3,
+			backoff: 500,
+			failure_threshold: 3,
+			cooldown: 60,
+		}
+	}
+}
+
+/// what an endpoint did since the collector started
+#[derive(Serialize, Clone, Debug)]
+pub struct EndpointStats {
+	pub endpoint: String,
+	pub healthy: bool,
+	/// requests served, by method (quotes are `eth_call`s)
+	pub served: HashMap<String, u64>,
+	pub failed: u64,
+	pub last_error: Option<String>,
+}
+
+#[derive(Default)]
+struct Health {
+	consecutive_failures: u32,
+	unhealthy_until: Option<Instant>,
+	served: HashMap<String, u64>,
+	failed: u64,
+	last_error: Option<String>,
+}
+
+struct Endpoint {
+	// host of the url, the rest of it can hold api keys
+	name: String,
+	client: Http,
+	health: Mutex<Health>,
+}
+
+impl Endpoint {
+	// the counters are still usable if a thread panicked while holding the lock
+	fn health(&self) -> MutexGuard<'_, Health> {
+		self.health.lock().unwrap_or_else(PoisonError::into_inner)
+	}
+
+	fn is_healthy(&self, now: Instant) -> bool {
+		self
+			.health()
+			.unhealthy_until
+			.map_or(true, |until| until <= now)
+	}
+
+	fn record_success(&self, method: &str) {
+		let mut health = self.health();
+		if health.unhealthy_until.take().is_some() {
+			info!(endpoint = self.name, "rpc endpoint recovered");
+		}
+
+		health.consecutive_failures = 0;
+		*health.served.entry(method.to_string()).or_default() += 1;
+	}
+
+	fn record_failure(&self, error: &HttpClientError, policy: &RetryPolicy) {
+		let mut health = self.health();
+		health.consecutive_failures += 1;
+		health.failed += 1;
+		health.last_error = Some(error.to_string());
+
+		if health.consecutive_failures >= policy.failure_threshold {
+			let cooldown = Duration::from_secs(policy.cooldown);
+			warn!(
+				endpoint = self.name,
+				failures = health.consecutive_failures,
+				cooldown = ?cooldown,
+				"rpc endpoint marked unhealthy"
+			);
+			health.unhealthy_until = Some(Instant::now() + cooldown);
+		}
+	}
+}
+
+/// json-rpc client over several endpoints, tried in order. endpoints that keep failing are only
+/// used once every healthy endpoint failed as well, until their cooldown is over
+#[derive(Clone)]
+pub struct FailoverClient {
+	endpoints: Arc<Vec<Endpoint>>,
+	policy: RetryPolicy,
+}
+
+impl Debug for FailoverClient {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.debug_list()
+			.entries(self.endpoints.iter().map(|endpoint| &endpoint.name))
+			.finish()
+	}
+}
+
+impl FailoverClient {
+	pub fn new(urls: &[String], policy: RetryPolicy) -> eyre::Result<Self> {
+		if urls.is_empty() {
+			return Err(eyre!("no rpc endpoints"));
+		}
+
+		let endpoints = urls
+			.iter()
+			.map(|url| {
+				let url = Url::parse(url).wrap_err("rpc url is not valid")?;
+
+				Ok(Endpoint {
+					name: url.host_str().unwrap_or("unknown").to_string(),
+					client: Http::new(url),
+					health: Mutex::new(Health::default()),
+				})
+			})
+			.collect::<eyre::Result<Vec<Endpoint>>>()?;
+
+		Ok(Self {
+			endpoints: Arc::new(endpoints),
+			policy,
+		})
+	}
+
+	pub fn stats(&self) -> Vec<EndpointStats> {
+		let now = Instant::now();
+
+		self
+			.endpoints
+			.iter()
+			.map(|endpoint| {
+				let healthy = endpoint.is_healthy(now);
+				let health = endpoint.health();
+
+				EndpointStats {
+					endpoint: endpoint.name.clone(),
+					healthy,
+					served: health.served.clone(),
+					failed: health.failed,
+					last_error: health.last_error.clone(),
+				}
+			})
+			.collect()
+	}
+
+	// healthy endpoints first, unhealthy ones as a last resort, both in configured order
+	fn ordered(&self) -> Vec<&Endpoint> {
+		let now = Instant::now();
+		let (mut healthy, unhealthy): (Vec<&Endpoint>, Vec<&Endpoint>) = self
+			.endpoints
+			.iter()
+			.partition(|endpoint| endpoint.is_healthy(now));
+
+		healthy.extend(unhealthy);
+		healthy
+	}
+}
+
+// whether the call reverted, in which case every other node would answer the same. any other
+// error, like a node that has not seen the pinned block yet, is worth trying elsewhere
+fn is_rejection(error: &HttpClientError) -> bool {
+	matches!(
+		error,
+		HttpClientError::JsonRpcError(error)
+			if error.code == EXECUTION_REVERTED || error.message.contains("execution reverted")
+	)
+}
+
+#[async_trait]
+impl JsonRpcClient for FailoverClient {
+	type Error = FailoverError;
+
+	async fn request<T, R>(&self, method: &str, params: T) -> Result<R, FailoverError>
+	where
+		T: Debug + Serialize + Send + Sync,
+		R: DeserializeOwned + Send,
+	{
+		let mut last_error = None;
+
+		for attempt in 0..self.policy.attempts {
+			if attempt > 0 {
+				let backoff = Duration::from_millis(self.policy.backoff) * 2_u32.pow(attempt - 1);
+				warn!(method, attempt, backoff = ?backoff, "every rpc endpoint failed, retrying");
+				tokio::time::sleep(backoff).await;
+			}
+
+			for endpoint in self.ordered() {
+				match endpoint.client.request(method, &params).await {
+					Ok(response) => {
+						endpoint.record_success(method);
+						debug!(
+							endpoint = endpoint.name,
+							method, attempt, "rpc request served"
+						);
+						return Ok(response);
+					}
+					Err(error) if is_rejection(&error) => {
+						endpoint.record_success(method);
+						return Err(FailoverError::Rejected(error));
+					}
+					Err(error) => {
+						warn!(error = ?error, endpoint = endpoint.name, method, "rpc request failed");
+						endpoint.record_failure(&error, &self.policy);
+						last_error = Some(error);
+					}
+				}
+			}
+		}
+
+		Err(FailoverError::Exhausted {
+			attempts: self.policy.attempts,
+			error: last_error,
+		})
+	}
+}
+
+#[derive(Debug)]
+pub enum FailoverError {
+	/// the call reverted
+	Rejected(HttpClientError),
+	/// every endpoint failed on every attempt, holds the last error
+	Exhausted {
+		attempts: u32,
+		error: Option<HttpClientError>,
+	},
+}
+
+impl FailoverError {
+	fn inner(&self) -> Option<&HttpClientError> {
+		match self {
+			FailoverError::Rejected(error) => Some(error),
+			FailoverError::Exhausted { error, .. } => error.as_ref(),
+		}
+	}
+}
+
+impl fmt::Display for FailoverError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			FailoverError::Rejected(error) => write!(f, "rpc call reverted: {}", error),
+			FailoverError::Exhausted {
+				attempts,
+				error: Some(error),
+			} => write!(
+				f,
+				"every rpc endpoint failed {} times, last error: {}",
+				attempts, error
+			),
+			FailoverError::Exhausted { attempts, .. } => {
+				write!(f, "every rpc endpoint failed {} times", attempts)
+			}
+		}
+	}
+}
+
+impl std::error::Error for FailoverError {
+	fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+		self
+			.inner()
+			.map(|error| error as &(dyn std::error::Error + 'static))
+	}
+}
+
+impl RpcError for FailoverError {
+	fn as_error_response(&self) -> Option<&JsonRpcError> {
+		self.inner()?.as_error_response()
+	}
+
+	fn as_serde_error(&self) -> Option<&serde_json::Error> {
+		self.inner()?.as_serde_error()
+	}
+}
+
+impl From<FailoverError> for ProviderError {
+	fn from(error: FailoverError) -> Self {
+		ProviderError::JsonRpcClientError(Box::new(error))
+	}
+}
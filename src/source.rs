@@ -0,0 +1,303 @@
+use std::cmp::Ordering;
+use std::time::Duration;
+
+use ethers::prelude::*;
+use eyre::{OptionExt, Result};
+use futures::future::{self, BoxFuture, FutureExt};
+use shared::coin::Pair;
+use tracing::{debug, error, warn};
+
+use crate::chainlink::{fetch_chainlink_prices, CHAINLINK_SOURCE};
+use crate::config::PairConfig;
+use crate::datapoint::{KrakenInterval, Provenance};
+use crate::fallback::{FallbackSource, KRAKEN};
+use crate::fixes::try_fetch_kraken_datapoints;
+use crate::price::{fetch_prices, Quote};
+
+pub const QUOTER_SOURCE: &str = "quoter";
+pub const KRAKEN_SOURCE: &str = "kraken";
+
+// collections run every few minutes, a kraken price that takes longer is left out
+const KRAKEN_SOURCE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// names of every price source
+pub const SOURCE_NAMES: [&str; 3] = [QUOTER_SOURCE, CHAINLINK_SOURCE, KRAKEN_SOURCE];
+
+pub trait PriceSource: Send + Sync {
+	/// name the prices of this source are stored under
+	fn name(&self) -> &'static str;
+
+	/// where the prices of this source come from
+	fn provenance(&self) -> Provenance;
+
+	/// whether prices are read from the chain, and so belong to the block they were read at
+	fn on_chain(&self) -> bool {
+		self.provenance() == Provenance::OnChain
+	}
+
+	/// fetches the current price of every pair this source supports,
+	/// on-chain sources read their prices at `block`
+	fn fetch_prices<'a>(
+		&'a self,
+		pairs: &'a [PairConfig],
+		block: BlockId,
+	) -> BoxFuture<'a, Vec<PairPrice>>;
+}
+
+/// price of a pair from a single source
+#[derive(Clone, Debug)]
+pub struct PairPrice {
+	pub pair: Pair,
+	pub price: f64,
+	/// fee tier of every pool a quote went through, in hundredths of a bip
+	pub fees: Option<Vec<u32>>,
+}
+
+impl PairPrice {
+	pub fn new(pair: Pair, price: f64) -> Self {
+		Self {
+			pair,
+			price,
+			fees: None,
+		}
+	}
+
+	pub fn with_fees(mut self, fees: Vec<u32>) -> Self {
+		self.fees = Some(fees);
+		self
+	}
+}
+
+/// uniswap v3 quoter, quoting each pair through its configured route
+pub struct QuoterSource<P> {
+	provider: Provider<P>,
+	quoter: Address,
+}
+
+impl<P> QuoterSource<P> {
+	pub fn new(provider: Provider<P>, quoter: Address) -> Self {
+		Self { provider, quoter }
+	}
+}
+
+impl<P: JsonRpcClient + Clone + 'static> PriceSource for QuoterSource<P> {
+	fn name(&self) -> &'static str {
+		QUOTER_SOURCE
+	}
+
+	fn provenance(&self) -> Provenance {
+		Provenance::OnChain
+	}
+
+	fn fetch_prices<'a>(
+		&'a self,
+		pairs: &'a [PairConfig],
+		block: BlockId,
+	) -> BoxFuture<'a, Vec<PairPrice>> {
+		async move {
+			fetch_prices(self.provider.clone(), self.quoter, pairs, block)
+				.await
+				.into_iter()
+				.map(|Quote { pair, price, fees }| {
+					debug!(pair = ?pair, fees = ?fees, "quoted price");
+					PairPrice::new(pair, price).with_fees(fees.iter().map(|fee| *fee as u32).collect())
+				})
+				.collect()
+		}
+		.boxed()
+	}
+}
+
+/// chainlink aggregators, only pairs with a configured feed are fetched
+pub struct ChainlinkSource<P> {
+	provider: Provider<P>,
+}
+
+impl<P> ChainlinkSource<P> {
+	pub fn new(provider: Provider<P>) -> Self {
+		Self { provider }
+	}
+}
+
+impl<P: JsonRpcClient + Clone + 'static> PriceSource for ChainlinkSource<P> {
+	fn name(&self) -> &'static str {
+		CHAINLINK_SOURCE
+	}
+
+	fn provenance(&self) -> Provenance {
+		Provenance::OnChain
+	}
+
+	fn fetch_prices<'a>(
+		&'a self,
+		pairs: &'a [PairConfig],
+		block: BlockId,
+	) -> BoxFuture<'a, Vec<PairPrice>> {
+		async move {
+			fetch_chainlink_prices(self.provider.clone(), pairs, block)
+				.await
+				.into_iter()
+				.map(|(pair, price)| PairPrice::new(pair, price))
+				.collect()
+		}
+		.boxed()
+	}
+}
+
+/// last close of kraken's minute candles, only pairs with a kraken fallback symbol are fetched
+pub struct KrakenSource {
+	client: reqwest::Client,
+}
+
+impl KrakenSource {
+	pub fn new() -> Result<Self> {
+		Ok(Self {
+			client: reqwest::Client::builder()
+				.timeout(KRAKEN_SOURCE_TIMEOUT)
+				.build()?,
+		})
+	}
+}
+
+impl PriceSource for KrakenSource {
+	fn name(&self) -> &'static str {
+		KRAKEN_SOURCE
+	}
+
+	fn provenance(&self) -> Provenance {
+		Provenance::Kraken
+	}
+
+	// every collection waits on this, so a slow or rate limited kraken is skipped instead of
+	// retried, the other sources still make the median
+	fn fetch_prices<'a>(
+		&'a self,
+		pairs: &'a [PairConfig],
+		_block: BlockId,
+	) -> BoxFuture<'a, Vec<PairPrice>> {
+		async move {
+			future::join_all(
+				pairs
+					.iter()
+					.filter_map(|config| Some((config, config.fallback_symbol(KRAKEN.name())?)))
+					.map(|(config, symbol)| async move {
+						let datapoints =
+							try_fetch_kraken_datapoints(&self.client, symbol, &KrakenInterval::Minute).await?;
+						let datapoint = datapoints
+							.last()
+							.ok_or_eyre("kraken returned no datapoints")?;
+
+						Ok(PairPrice::new(config.pair.clone(), datapoint.close as f64))
+					}),
+			)
+			.await
+			.into_iter()
+			.filter_map(|x: Result<PairPrice>| {
+				if let Err(ref error) = x {
+					error!(error = ?error, "error getting kraken price");
+				}
+
+				x.ok()
+			})
+			.collect()
+		}
+		.boxed()
+	}
+}
+
+#[derive(Clone, Debug)]
+pub struct SourcePrice {
+	pub source: &'static str,
+	pub price: f64,
+	pub provenance: Provenance,
+	/// fee tiers the quoter went through, see `PairPrice`
+	pub fees: Option<Vec<u32>>,
+}
+
+impl SourcePrice {
+	pub fn on_chain(&self) -> bool {
+		self.provenance == Provenance::OnChain
+	}
+}
+
+#[derive(Clone, Debug)]
+pub struct AggregatedPrice {
+	pub pair: Pair,
+	/// median of all source prices
+	pub price: f64,
+	pub sources: Vec<SourcePrice>,
+}
+
+impl AggregatedPrice {
+	/// the median only counts as on-chain, and so belongs to a block, when every price that went
+	/// into it was read from the chain. sources of different provenance make it aggregated
+	pub fn provenance(&self) -> Provenance {
+		let mut provenances = self.sources.iter().map(|source| source.provenance);
+		let Some(first) = provenances.next() else {
+			return Provenance::Unknown;
+		};
+
+		match provenances.all(|provenance| provenance == first) {
+			true => first,
+			false => Provenance::Aggregated,
+		}
+	}
+}
+
+fn median(mut prices: Vec<f64>) -> Option<f64> {
+	prices.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+	let middle = prices.len() / 2;
+	match prices.len() {
+		0 => None,
+		length if length % 2 == 0 => Some((prices[middle - 1] + prices[middle]) / 2.0),
+		_ => Some(prices[middle]),
+	}
+}
+
+/// queries every source and takes the median price of each pair
+pub async fn aggregate_prices<S: AsRef<dyn PriceSource> + Sync>(
+	sources: &[S],
+	pairs: &[PairConfig],
+	block: BlockId,
+) -> Vec<AggregatedPrice> {
+	let source_prices = future::join_all(
+		sources
+			.iter()
+			.map(|source| source.as_ref())
+			.map(|source| async move { (source, source.fetch_prices(pairs, block).await) }),
+	)
+	.await;
+
+	pairs
+		.iter()
+		.filter_map(|PairConfig { pair, .. }| {
+			let sources: Vec<SourcePrice> = source_prices
+				.iter()
+				.filter_map(|(source, prices)| {
+					prices
+						.iter()
+						.find(|price| price.pair.to_string() == pair.to_string())
+						.map(|price| SourcePrice {
+							source: source.name(),
+							price: price.price,
+							provenance: source.provenance(),
+							fees: price.fees.clone(),
+						})
+				})
+				.collect();
+
+			let Some(price) = median(sources.iter().map(|source| source.price).collect()) else {
+				warn!(pair = ?pair, "no source returned a price");
+				return None;
+			};
+
+			debug!(pair = ?pair, price, sources = ?sources, "aggregated price");
+			Some(AggregatedPrice {
+				pair: pair.clone(),
+				price,
+				sources,
+			})
+		})
+		.collect()
+}
@@ -0,0 +1,83 @@
+mod memory;
+mod redis;
+mod sqlite;
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::{DateTime, DurationRound, TimeDelta};
+use ethers::types::Chain;
+use eyre::{OptionExt, Result};
+use shared::coin::Pair;
+
+use crate::datapoint::{Datapoint, Trade};
+
+pub use self::memory::MemoryStore;
+pub use self::redis::RedisStore;
+pub use self::sqlite::SqliteStore;
+
+/// the store of every collected chain
+pub type ChainStores = HashMap<Chain, Arc<dyn PriceStore>>;
+
+pub trait PriceStore: Send + Sync {
+	/// appends datapoints (sorted oldest first) to the series of `pair`, datapoints at or before
+	/// the newest stored timestamp are skipped. returns how many were stored
+	fn store_prices(&self, pair: &Pair, datapoints: Vec<Datapoint>) -> Result<usize>;
+
+	/// inserts datapoints into the series of `pair` at the position of their timestamps,
+	/// datapoints with an already stored timestamp are skipped
+	fn insert_prices(&self, pair: &Pair, datapoints: Vec<Datapoint>) -> Result<()>;
+
+	/// like `store_prices`, but for the prices of a single source, kept next to the series of
+	/// `pair` for comparison
+	fn store_source_prices(
+		&self,
+		pair: &Pair,
+		source: &str,
+		datapoints: Vec<Datapoint>,
+	) -> Result<usize>;
+
+	/// newest stored timestamp of `pair`, `None` if nothing has been stored yet
+	fn last_timestamp(&self, pair: &Pair) -> Result<Option<i64>>;
+
+	/// every stored timestamp of `pair`, sorted oldest first
+	fn get_timestamps(&self, pair: &Pair) -> Result<Vec<i64>>;
+
+	/// newest `amount` datapoints at or before `at` (or the latest datapoint), sorted oldest first
+	fn get_datapoints(&self, pair: &Pair, amount: usize, at: Option<i64>) -> Result<Vec<Datapoint>>;
+
+	/// like `get_datapoints`, but for the prices of a single source
+	fn get_source_datapoints(
+		&self,
+		pair: &Pair,
+		source: &str,
+		amount: usize,
+		at: Option<i64>,
+	) -> Result<Vec<Datapoint>>;
+
+	/// checks the stored series of `pair` for inconsistencies and repairs them
+	fn repair(&self, pair: &Pair) -> Result<()>;
+
+	/// stores swaps in the pool of `pair`, trades that are already stored are skipped
+	fn store_trades(&self, pair: &Pair, trades: Vec<Trade>) -> Result<()>;
+
+	/// trades of `pair` from `from` up to, but not including, `to`, sorted oldest first
+	fn get_trades(&self, pair: &Pair, from: i64, to: i64) -> Result<Vec<Trade>>;
+
+	/// removes the trade of `pair` at `log_index` in block `block_number`, for swaps of blocks that
+	/// were reorged out. returns whether there was one
+	fn remove_trade(&self, pair: &Pair, block_number: u64, log_index: u64) -> Result<bool>;
+}
+
+// stored timestamps are always rounded to the minute
+fn round_timestamp(timestamp: i64) -> Result<i64> {
+	let duration =
+		TimeDelta::try_minutes(1).expect("1 minute did not convert into timedelta propperly");
+
+	Ok(
+		DateTime::from_timestamp(timestamp, 0)
+			.ok_or_eyre("timestamp did not convert to DateTime")?
+			.duration_round(duration)?
+			.timestamp(),
+	)
+}
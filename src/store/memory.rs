@@ -0,0 +1,324 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, MutexGuard};
+
+use eyre::{eyre, Result};
+use shared::coin::Pair;
+
+use super::{round_timestamp, PriceStore};
+use crate::datapoint::{Datapoint, Trade};
+
+/// non-persistent store, mainly useful for testing without a redis server
+#[derive(Default)]
+pub struct MemoryStore {
+	series: Mutex<HashMap<String, Vec<Datapoint>>>,
+	trades: Mutex<HashMap<String, Vec<Trade>>>,
+}
+
+impl MemoryStore {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	fn key(pair: &Pair, source: Option<&str>) -> String {
+		match source {
+			Some(source) => format!("{}:{}", pair, source),
+			None => pair.to_string(),
+		}
+	}
+
+	fn series(&self) -> Result<MutexGuard<'_, HashMap<String, Vec<Datapoint>>>> {
+		self
+			.series
+			.lock()
+			.map_err(|_| eyre!("memory store lock was poisoned"))
+	}
+
+	fn trades(&self) -> Result<MutexGuard<'_, HashMap<String, Vec<Trade>>>> {
+		self
+			.trades
+			.lock()
+			.map_err(|_| eyre!("memory store lock was poisoned"))
+	}
+
+	fn round(datapoints: Vec<Datapoint>) -> Result<Vec<Datapoint>> {
+		datapoints
+			.into_iter()
+			.map(|datapoint| {
+				Ok(Datapoint {
+					timestamp: round_timestamp(datapoint.timestamp)?,
+					..datapoint
+				})
+			})
+			.collect()
+	}
+
+	fn push(&self, key: String, datapoints: Vec<Datapoint>) -> Result<usize> {
+		let datapoints = Self::round(datapoints)?;
+		let mut series = self.series()?;
+		let series = series.entry(key).or_default();
+
+		let mut count = 0;
+		for datapoint in datapoints {
+			if series
+				.last()
+				.is_some_and(|last| last.timestamp >= datapoint.timestamp)
+			{
+				continue;
+			}
+
+			series.push(datapoint);
+			count += 1;
+		}
+
+		Ok(count)
+	}
+
+	fn range(&self, key: String, amount: usize, at: Option<i64>) -> Result<Vec<Datapoint>> {
+		let mut datapoints: Vec<Datapoint> = self
+			.series()?
+			.get(&key)
+			.map(|datapoints| {
+				datapoints
+					.iter()
+					.filter(|datapoint| !matches!(at, Some(at) if datapoint.timestamp > at))
+					.cloned()
+					.collect()
+			})
+			.unwrap_or_default();
+
+		let excess = datapoints.len().saturating_sub(amount);
+		datapoints.drain(..excess);
+
+		Ok(datapoints)
+	}
+}
+
+impl PriceStore for MemoryStore {
+	fn store_prices(&self, pair: &Pair, datapoints: Vec<Datapoint>) -> Result<usize> {
+		self.push(Self::key(pair, None), datapoints)
+	}
+
+	fn insert_prices(&self, pair: &Pair, datapoints: Vec<Datapoint>) -> Result<()> {
+		let datapoints = Self::round(datapoints)?;
+		let mut series = self.series()?;
+		let series = series.entry(Self::key(pair, None)).or_default();
+
+		for datapoint in datapoints {
+			if let Err(index) = series.binary_search_by_key(&datapoint.timestamp, |d| d.timestamp) {
+				series.insert(index, datapoint);
+			}
+		}
+
+		Ok(())
+	}
+
+	fn store_source_prices(
+		&self,
+		pair: &Pair,
+		source: &str,
+		datapoints: Vec<Datapoint>,
+	) -> Result<usize> {
+		self.push(Self::key(pair, Some(source)), datapoints)
+	}
+
+	fn last_timestamp(&self, pair: &Pair) -> Result<Option<i64>> {
+		Ok(
+			self
+				.series()?
+				.get(&Self::key(pair, None))
+				.and_then(|datapoints| datapoints.last())
+				.map(|datapoint| datapoint.timestamp),
+		)
+	}
+
+	fn get_timestamps(&self, pair: &Pair) -> Result<Vec<i64>> {
+		Ok(
+			self
+				.series()?
+				.get(&Self::key(pair, None))
+				.map(|datapoints| datapoints.iter().map(|d| d.timestamp).collect())
+				.unwrap_or_default(),
+		)
+	}
+
+	fn get_datapoints(&self, pair: &Pair, amount: usize, at: Option<i64>) -> Result<Vec<Datapoint>> {
+		self.range(Self::key(pair, None), amount, at)
+	}
+
+	fn get_source_datapoints(
+		&self,
+		pair: &Pair,
+		source: &str,
+		amount: usize,
+		at: Option<i64>,
+	) -> Result<Vec<Datapoint>> {
+		self.range(Self::key(pair, Some(source)), amount, at)
+	}
+
+	fn repair(&self, _pair: &Pair) -> Result<()> {
+		// prices and timestamps are stored together, so they can't go out of alignment
+		Ok(())
+	}
+
+	fn store_trades(&self, pair: &Pair, trades: Vec<Trade>) -> Result<()> {
+		let mut stored = self.trades()?;
+		let stored = stored.entry(Self::key(pair, None)).or_default();
+
+		// a log is identified by its position in the chain, which also orders the trades
+		for trade in trades {
+			if let Err(index) = stored
+				.binary_search_by_key(&(trade.block_number, trade.log_index), |stored| {
+					(stored.block_number, stored.log_index)
+				}) {
+				stored.insert(index, trade);
+			}
+		}
+
+		Ok(())
+	}
+
+	fn get_trades(&self, pair: &Pair, from: i64, to: i64) -> Result<Vec<Trade>> {
+		Ok(
+			self
+				.trades()?
+				.get(&Self::key(pair, None))
+				.map(|trades| {
+					trades
+						.iter()
+						.filter(|trade| (from..to).contains(&trade.timestamp))
+						.copied()
+						.collect()
+				})
+				.unwrap_or_default(),
+		)
+	}
+
+	fn remove_trade(&self, pair: &Pair, block_number: u64, log_index: u64) -> Result<bool> {
+		let mut stored = self.trades()?;
+		let Some(stored) = stored.get_mut(&Self::key(pair, None)) else {
+			return Ok(false);
+		};
+
+		match stored.binary_search_by_key(&(block_number, log_index), |stored| {
+			(stored.block_number, stored.log_index)
+		}) {
+			Ok(index) => {
+				stored.remove(index);
+				Ok(true)
+			}
+			Err(_) => Ok(false),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::datapoint::{Datapoint, TimeType};
+	use crate::store::PriceStore;
+	use crate::testing::{datapoint, memory_store, prices, START};
+
+	#[test]
+	fn stored_prices_are_rounded_and_read_oldest_first() {
+		let (store, pair) = memory_store();
+		store
+			.store_prices(
+				&pair,
+				vec![
+					datapoint(0, 1.0),
+					// collected a few seconds late
+					Datapoint::new(2.0, TimeType::Timestamp(START + 5 * 60 + 3)).unwrap(),
+					datapoint(10, 3.0),
+				],
+			)
+			.unwrap();
+
+		assert_eq!(
+			prices(&store.get_datapoints(&pair, 10, None).unwrap()),
+			[(0, 1.0), (5, 2.0), (10, 3.0)]
+		);
+		assert_eq!(
+			prices(&store.get_datapoints(&pair, 2, None).unwrap()),
+			[(5, 2.0), (10, 3.0)]
+		);
+		assert_eq!(store.last_timestamp(&pair).unwrap(), Some(START + 10 * 60));
+	}
+
+	#[test]
+	fn inserted_prices_fill_gaps_without_replacing() {
+		let (store, pair) = memory_store();
+		store
+			.store_prices(&pair, vec![datapoint(0, 1.0), datapoint(15, 4.0)])
+			.unwrap();
+		store
+			.insert_prices(
+				&pair,
+				vec![datapoint(10, 3.0), datapoint(5, 2.0), datapoint(15, 40.0)],
+			)
+			.unwrap();
+
+		assert_eq!(
+			prices(&store.get_datapoints(&pair, 10, None).unwrap()),
+			[(0, 1.0), (5, 2.0), (10, 3.0), (15, 4.0)]
+		);
+		assert_eq!(
+			store.get_timestamps(&pair).unwrap(),
+			[0, 5, 10, 15].map(|minute| START + minute * 60)
+		);
+	}
+
+	#[test]
+	fn datapoints_at_leave_out_newer_ones() {
+		let (store, pair) = memory_store();
+		store
+			.store_prices(
+				&pair,
+				(0..6)
+					.map(|slot| datapoint(slot * 5, slot as f64))
+					.collect(),
+			)
+			.unwrap();
+
+		assert_eq!(
+			prices(
+				&store
+					.get_datapoints(&pair, 2, Some(START + 12 * 60))
+					.unwrap()
+			),
+			[(5, 1.0), (10, 2.0)]
+		);
+		assert_eq!(
+			prices(
+				&store
+					.get_datapoints(&pair, 10, Some(START + 10 * 60))
+					.unwrap()
+			),
+			[(0, 0.0), (5, 1.0), (10, 2.0)]
+		);
+		assert!(store
+			.get_datapoints(&pair, 10, Some(START - 60))
+			.unwrap()
+			.is_empty());
+	}
+
+	#[test]
+	fn source_prices_are_kept_apart() {
+		let (store, pair) = memory_store();
+		store.store_prices(&pair, vec![datapoint(0, 1.0)]).unwrap();
+		store
+			.store_source_prices(&pair, "chainlink", vec![datapoint(0, 1.1)])
+			.unwrap();
+
+		assert_eq!(
+			prices(&store.get_datapoints(&pair, 10, None).unwrap()),
+			[(0, 1.0)]
+		);
+		assert_eq!(
+			prices(
+				&store
+					.get_source_datapoints(&pair, "chainlink", 10, None)
+					.unwrap()
+			),
+			[(0, 1.1)]
+		);
+	}
+}
@@ -0,0 +1,762 @@
+use std::collections::HashSet;
+use std::iter::{self, zip};
+
+use ethers::types::Chain;
+use eyre::{Context, Result};
+use redis::{Client, Commands, Connection};
+use shared::coin::Pair;
+use tracing::{debug, error, info, instrument, warn};
+
+use super::{round_timestamp, PriceStore};
+use crate::datapoint::{BlockInfo, Datapoint, Provenance, SourceCandle, TimeType, Trade, Volume};
+use crate::COLLECTION_INTERVAL;
+
+// source the entries of drifted lists are moved to by `repair`
+const DRIFTED_SOURCE: &str = "drifted";
+
+// fields of a timestamp keyed hash whose timestamp is one of `timestamps`
+fn fields_at<'a, T>(
+	fields: &'a [(String, T)],
+	timestamps: &HashSet<String>,
+) -> Vec<(&'a String, &'a T)> {
+	fields
+		.iter()
+		.filter(|(timestamp, _)| timestamps.contains(timestamp))
+		.map(|(timestamp, value)| (timestamp, value))
+		.collect()
+}
+
+// index of the first timestamp that is not one collection interval after the one before it,
+// timestamps collected a little late still count as on time
+fn first_gap(timestamps: &[i64], interval_secs: i64) -> Option<usize> {
+	timestamps
+		.windows(2)
+		.position(|window| {
+			let step = window[1] - window[0];
+			step <= 0 || step > interval_secs * 3 / 2
+		})
+		.map(|index| index + 1)
+}
+
+/// stores each pair as two parallel lists, `{chain}:{pair}:prices` and `{chain}:{pair}:timestamps`,
+/// prices of a single source go into `{chain}:{pair}:{source}:prices` and
+/// `{chain}:{pair}:{source}:timestamps`. blocks of on-chain prices are kept in the
+/// `{chain}:{pair}:blocks` hash, fallback candles of backfilled prices in the `{chain}:{pair}:candles`
+/// hash, on-chain volumes of collected prices in the `{chain}:{pair}:volumes` hash, fee tiers of
+/// quoted prices in the `{chain}:{pair}:quoter:fees` hash and the provenance of every datapoint in
+/// the `{chain}:{pair}:provenance` hash, all keyed by timestamp. swaps go into the
+/// `{chain}:{pair}:trades` sorted set as json, scored by timestamp, and the
+/// `{chain}:{pair}:trade_ids` hash from `{block}:{log index}` to that json. entries of lists that
+/// drifted apart are kept unpaired in `{chain}:{pair}:drifted:prices` and
+/// `{chain}:{pair}:drifted:timestamps`
+pub struct RedisStore {
+	client: Client,
+	chain: Chain,
+}
+
+impl RedisStore {
+	/// moves the keys of `pairs` from before chains were scoped into their chain
+	pub fn open<'a>(
+		client: Client,
+		chain: Chain,
+		pairs: impl IntoIterator<Item = &'a Pair>,
+	) -> Result<Self> {
+		let store = Self { client, chain };
+
+		let mut connection = store.client.get_connection()?;
+		for pair in pairs {
+			store.scope_legacy_keys(&mut connection, pair)?;
+		}
+
+		Ok(store)
+	}
+
+	fn prefix(&self, pair: &Pair, source: Option<&str>) -> String {
+		match source {
+			Some(source) => format!("{}:{}:{}", self.chain, pair, source),
+			None => format!("{}:{}", self.chain, pair),
+		}
+	}
+
+	fn keys(&self, pair: &Pair, source: Option<&str>) -> (String, String) {
+		let prefix = self.prefix(pair, source);
+
+		(
+			format!("{}:prices", prefix),
+			format!("{}:timestamps", prefix),
+		)
+	}
+
+	fn blocks_key(&self, pair: &Pair, source: Option<&str>) -> String {
+		format!("{}:blocks", self.prefix(pair, source))
+	}
+
+	fn candles_key(&self, pair: &Pair, source: Option<&str>) -> String {
+		format!("{}:candles", self.prefix(pair, source))
+	}
+
+	fn volumes_key(&self, pair: &Pair, source: Option<&str>) -> String {
+		format!("{}:volumes", self.prefix(pair, source))
+	}
+
+	fn fees_key(&self, pair: &Pair, source: Option<&str>) -> String {
+		format!("{}:fees", self.prefix(pair, source))
+	}
+
+	fn provenance_key(&self, pair: &Pair, source: Option<&str>) -> String {
+		format!("{}:provenance", self.prefix(pair, source))
+	}
+
+	fn trades_key(&self, pair: &Pair) -> String {
+		format!("{}:trades", self.prefix(pair, None))
+	}
+
+	fn trade_ids_key(&self, pair: &Pair) -> String {
+		format!("{}:trade_ids", self.prefix(pair, None))
+	}
+
+	// a log is identified by its position in the chain
+	fn trade_id(block_number: u64, log_index: u64) -> String {
+		format!("{}:{}", block_number, log_index)
+	}
+
+	// keys used to have no chain in them, back when only mainnet was collected
+	fn scope_legacy_keys(&self, connection: &mut Connection, pair: &Pair) -> Result<()> {
+		if self.chain != Chain::Mainnet {
+			return Ok(());
+		}
+
+		let legacy_keys: Vec<String> = connection.scan_match(format!("{}:*", pair))?.collect();
+		for key in legacy_keys {
+			let scoped_key = format!("{}:{}", self.chain, key);
+			match connection.rename_nx(&key, &scoped_key)? {
+				true => info!(key, scoped_key, "moved key into its chain"),
+				false => warn!(
+					key,
+					scoped_key, "chain scoped key already exists, leaving key as is"
+				),
+			}
+		}
+
+		Ok(())
+	}
+
+	// (timestamp, block) fields for the blocks hash
+	fn blocks(datapoints: &[Datapoint]) -> Result<Vec<(String, String)>> {
+		datapoints
+			.iter()
+			.filter_map(|datapoint| Some((datapoint.timestamp, datapoint.block?)))
+			.map(|(timestamp, block)| {
+				Ok((
+					round_timestamp(timestamp)?.to_string(),
+					serde_json::to_string(&block)?,
+				))
+			})
+			.collect()
+	}
+
+	// (timestamp, candle) fields for the candles hash
+	fn candles(datapoints: &[Datapoint]) -> Result<Vec<(String, String)>> {
+		datapoints
+			.iter()
+			.filter_map(|datapoint| Some((datapoint.timestamp, datapoint.candle?)))
+			.map(|(timestamp, candle)| {
+				Ok((
+					round_timestamp(timestamp)?.to_string(),
+					serde_json::to_string(&candle)?,
+				))
+			})
+			.collect()
+	}
+
+	// (timestamp, volume) fields for the volumes hash
+	fn volumes(datapoints: &[Datapoint]) -> Result<Vec<(String, String)>> {
+		datapoints
+			.iter()
+			.filter_map(|datapoint| Some((datapoint.timestamp, datapoint.volume?)))
+			.map(|(timestamp, volume)| {
+				Ok((
+					round_timestamp(timestamp)?.to_string(),
+					serde_json::to_string(&volume)?,
+				))
+			})
+			.collect()
+	}
+
+	// (timestamp, fees) fields for the fees hash
+	fn fees(datapoints: &[Datapoint]) -> Result<Vec<(String, String)>> {
+		datapoints
+			.iter()
+			.filter_map(|datapoint| Some((datapoint.timestamp, datapoint.fees.as_ref()?)))
+			.map(|(timestamp, fees)| {
+				Ok((
+					round_timestamp(timestamp)?.to_string(),
+					serde_json::to_string(fees)?,
+				))
+			})
+			.collect()
+	}
+
+	// (timestamp, provenance) fields for the provenance hash
+	fn provenances(datapoints: &[Datapoint]) -> Result<Vec<(String, &'static str)>> {
+		datapoints
+			.iter()
+			.map(|datapoint| {
+				Ok((
+					round_timestamp(datapoint.timestamp)?.to_string(),
+					datapoint.provenance.as_str(),
+				))
+			})
+			.collect()
+	}
+
+	// rewrites both lists from the first inserted timestamp on with the datapoints merged in by
+	// timestamp, watching the lists so a concurrent push makes the merge start over instead of
+	// getting lost
+	fn insert(&self, pair: &Pair, source: Option<&str>, datapoints: Vec<Datapoint>) -> Result<()> {
+		if datapoints.is_empty() {
+			return Ok(());
+		}
+
+		let mut connection = self.client.get_connection()?;
+		debug!("redis connection established");
+
+		let inserted = datapoints
+			.iter()
+			.map(|datapoint| Ok((round_timestamp(datapoint.timestamp)?, datapoint.price)))
+			.collect::<Result<Vec<(i64, f64)>>>()?;
+		let first_inserted = inserted
+			.iter()
+			.map(|(timestamp, _)| *timestamp)
+			.min()
+			.unwrap_or(i64::MIN);
+		let blocks = Self::blocks(&datapoints)?;
+		let candles = Self::candles(&datapoints)?;
+		let volumes = Self::volumes(&datapoints)?;
+		let fees = Self::fees(&datapoints)?;
+		let provenances = Self::provenances(&datapoints)?;
+
+		let (prices_key, timestamps_key) = self.keys(pair, source);
+		let count: usize = redis::transaction(
+			&mut connection,
+			&[&prices_key, &timestamps_key],
+			|connection, pipe| {
+				let timestamps: Vec<i64> = connection.lrange(&timestamps_key, 0, -1)?;
+				// repairs are mostly recent, so the older part of the series is left where it is
+				let start = timestamps.partition_point(|timestamp| *timestamp < first_inserted);
+				let prices: Vec<f64> = connection.lrange(&prices_key, start as isize, -1)?;
+
+				let stored: HashSet<i64> = timestamps[start..].iter().copied().collect();
+				let count = inserted
+					.iter()
+					.map(|(timestamp, _)| *timestamp)
+					.filter(|timestamp| !stored.contains(timestamp))
+					.collect::<HashSet<i64>>()
+					.len();
+
+				// the sort is stable, so deduplicating keeps the already stored datapoint
+				let mut merged: Vec<(i64, f64)> = zip(timestamps[start..].iter().copied(), prices)
+					.chain(inserted.iter().copied())
+					.collect();
+				merged.sort_by_key(|(timestamp, _)| *timestamp);
+				merged.dedup_by_key(|(timestamp, _)| *timestamp);
+
+				let (timestamps, prices): (Vec<i64>, Vec<f64>) = merged.into_iter().unzip();
+
+				match start {
+					0 => pipe.del(&prices_key).ignore().del(&timestamps_key).ignore(),
+					start => pipe
+						.ltrim(&prices_key, 0, start as isize - 1)
+						.ignore()
+						.ltrim(&timestamps_key, 0, start as isize - 1)
+						.ignore(),
+				};
+
+				pipe
+					.rpush(&prices_key, prices)
+					.ignore()
+					.rpush(&timestamps_key, timestamps)
+					.ignore();
+
+				if !blocks.is_empty() {
+					pipe
+						.hset_multiple(self.blocks_key(pair, source), &blocks)
+						.ignore();
+				}
+
+				if !candles.is_empty() {
+					pipe
+						.hset_multiple(self.candles_key(pair, source), &candles)
+						.ignore();
+				}
+
+				if !volumes.is_empty() {
+					pipe
+						.hset_multiple(self.volumes_key(pair, source), &volumes)
+						.ignore();
+				}
+
+				if !fees.is_empty() {
+					pipe
+						.hset_multiple(self.fees_key(pair, source), &fees)
+						.ignore();
+				}
+
+				// an already stored datapoint is kept, so its provenance has to be kept as well
+				for (timestamp, provenance) in provenances.iter() {
+					pipe
+						.hset_nx(self.provenance_key(pair, source), timestamp, *provenance)
+						.ignore();
+				}
+
+				Ok(pipe.query::<Option<()>>(connection)?.map(|_| count))
+			},
+		)
+		.wrap_err("error inserting datapoints into redis")?;
+
+		info!(count = count, "inserted datapoints");
+		Ok(())
+	}
+
+	// appends the datapoints newer than the last stored timestamp, watching the timestamps so a
+	// concurrent push or insert makes this start over
+	fn push(&self, pair: &Pair, source: Option<&str>, datapoints: Vec<Datapoint>) -> Result<usize> {
+		let mut connection = self.client.get_connection()?;
+		debug!("redis connection established");
+
+		// convert everything up front so a bad timestamp can't leave the lists with different lengths
+		let rows = datapoints
+			.iter()
+			.map(|datapoint| {
+				Ok((
+					round_timestamp(datapoint.timestamp)?,
+					datapoint.price.to_string(),
+				))
+			})
+			.collect::<Result<Vec<(i64, String)>>>()?;
+
+		let blocks = Self::blocks(&datapoints)?;
+		let candles = Self::candles(&datapoints)?;
+		let volumes = Self::volumes(&datapoints)?;
+		let fees = Self::fees(&datapoints)?;
+		let provenances = Self::provenances(&datapoints)?;
+
+		let (prices_key, timestamps_key) = self.keys(pair, source);
+		let count: usize = redis::transaction(
+			&mut connection,
+			&[&prices_key, &timestamps_key],
+			|connection, pipe| {
+				let mut last: Option<i64> = connection.lindex(&timestamps_key, -1)?;
+				let rows: Vec<&(i64, String)> = rows
+					.iter()
+					.filter(|(timestamp, _)| {
+						if last.is_some_and(|last| last >= *timestamp) {
+							return false;
+						}
+
+						last = Some(*timestamp);
+						true
+					})
+					.collect();
+
+				if rows.is_empty() {
+					return Ok(Some(0));
+				}
+
+				let timestamps: HashSet<String> = rows
+					.iter()
+					.map(|(timestamp, _)| timestamp.to_string())
+					.collect();
+
+				pipe
+					.rpush(
+						&prices_key,
+						rows.iter().map(|(_, price)| price).collect::<Vec<_>>(),
+					)
+					.ignore()
+					.rpush(
+						&timestamps_key,
+						rows
+							.iter()
+							.map(|(timestamp, _)| timestamp)
+							.collect::<Vec<_>>(),
+					)
+					.ignore();
+
+				for (key, fields) in [
+					(
+						self.blocks_key(pair, source),
+						fields_at(&blocks, &timestamps),
+					),
+					(
+						self.candles_key(pair, source),
+						fields_at(&candles, &timestamps),
+					),
+					(
+						self.volumes_key(pair, source),
+						fields_at(&volumes, &timestamps),
+					),
+					(self.fees_key(pair, source), fields_at(&fees, &timestamps)),
+				] {
+					if !fields.is_empty() {
+						pipe.hset_multiple(key, &fields).ignore();
+					}
+				}
+
+				let provenances = fields_at(&provenances, &timestamps);
+				if !provenances.is_empty() {
+					pipe
+						.hset_multiple(self.provenance_key(pair, source), &provenances)
+						.ignore();
+				}
+
+				Ok(pipe.query::<Option<()>>(connection)?.map(|_| rows.len()))
+			},
+		)
+		.wrap_err("error pushing datapoints to redis")?;
+
+		if count < datapoints.len() {
+			debug!(
+				skipped = datapoints.len() - count,
+				"skipped datapoints at or before the last stored timestamp"
+			);
+		}
+
+		info!(count = count, "stored datapoints");
+		Ok(count)
+	}
+
+	fn range(
+		&self,
+		pair: &Pair,
+		source: Option<&str>,
+		amount: usize,
+		at: Option<i64>,
+	) -> Result<Vec<Datapoint>> {
+		let mut connection = self.client.get_connection()?;
+		let (prices_key, timestamps_key) = self.keys(pair, source);
+
+		let collection_interval_secs = COLLECTION_INTERVAL.std_duration().as_secs() as i64;
+
+		// convert timestamp into offset
+		let offset: i32 = match at {
+			Some(timestamp) => {
+				let current_timestamp: i64 = connection.lindex(&timestamps_key, -1)?;
+				(current_timestamp / collection_interval_secs - timestamp / collection_interval_secs) as i32
+			}
+			None => 0,
+		};
+
+		// apply corrections to amount and offset to prepare for querrying with redis
+		let offset = (offset * -1) as isize;
+		let amount = amount as isize * -1;
+
+		let timestamps: Vec<i64> = connection.lrange(timestamps_key, amount + offset, -1 + offset)?;
+		let prices: Vec<f64> = connection.lrange(prices_key, amount + offset, -1 + offset)?;
+
+		let mut field = |key: String| -> Result<Vec<Option<String>>> {
+			// hmget needs at least one field
+			match timestamps.is_empty() {
+				true => Ok(vec![]),
+				false => Ok(connection.hget(key, &timestamps)?),
+			}
+		};
+
+		let blocks = field(self.blocks_key(pair, source))?;
+		let candles = field(self.candles_key(pair, source))?;
+		let volumes = field(self.volumes_key(pair, source))?;
+		let fees = field(self.fees_key(pair, source))?;
+		let provenances = field(self.provenance_key(pair, source))?;
+
+		Ok(
+			zip(prices, timestamps)
+				.zip(blocks.into_iter().chain(iter::repeat(None)))
+				.zip(candles.into_iter().chain(iter::repeat(None)))
+				.zip(volumes.into_iter().chain(iter::repeat(None)))
+				.zip(fees.into_iter().chain(iter::repeat(None)))
+				.zip(provenances.into_iter().chain(iter::repeat(None)))
+				.map(
+					|((((((price, timestamp), block), candle), volume), fees), provenance)| {
+						let block = block
+							.map(|block| serde_json::from_str::<BlockInfo>(&block))
+							.transpose()?;
+						let candle = candle
+							.map(|candle| serde_json::from_str::<SourceCandle>(&candle))
+							.transpose()?;
+						let volume = volume
+							.map(|volume| serde_json::from_str::<Volume>(&volume))
+							.transpose()?;
+						let fees = fees
+							.map(|fees| serde_json::from_str::<Vec<u32>>(&fees))
+							.transpose()?;
+						let provenance = provenance
+							.map(|provenance| provenance.parse::<Provenance>())
+							.transpose()?
+							.unwrap_or_default();
+
+						Ok(
+							Datapoint::new(price, TimeType::Timestamp(timestamp))?
+								.with_block(block)
+								.with_candle(candle)
+								.with_volume(volume)
+								.with_fees(fees)
+								.with_provenance(provenance),
+						)
+					},
+				)
+				.filter_map(|x: Result<Datapoint>| x.ok())
+				.collect(),
+		)
+	}
+}
+
+impl PriceStore for RedisStore {
+	#[instrument(err, skip(self, datapoints))]
+	fn store_prices(&self, pair: &Pair, datapoints: Vec<Datapoint>) -> Result<usize> {
+		self.push(pair, None, datapoints)
+	}
+
+	#[instrument(err, skip(self, datapoints))]
+	fn insert_prices(&self, pair: &Pair, datapoints: Vec<Datapoint>) -> Result<()> {
+		self.insert(pair, None, datapoints)
+	}
+
+	#[instrument(err, skip(self, datapoints))]
+	fn store_source_prices(
+		&self,
+		pair: &Pair,
+		source: &str,
+		datapoints: Vec<Datapoint>,
+	) -> Result<usize> {
+		self.push(pair, Some(source), datapoints)
+	}
+
+	fn last_timestamp(&self, pair: &Pair) -> Result<Option<i64>> {
+		let mut connection = self.client.get_connection()?;
+		let (_, timestamps_key) = self.keys(pair, None);
+
+		Ok(connection.lindex(timestamps_key, -1)?)
+	}
+
+	fn get_timestamps(&self, pair: &Pair) -> Result<Vec<i64>> {
+		let mut connection = self.client.get_connection()?;
+		let (_, timestamps_key) = self.keys(pair, None);
+
+		Ok(connection.lrange(timestamps_key, 0, -1)?)
+	}
+
+	fn get_datapoints(&self, pair: &Pair, amount: usize, at: Option<i64>) -> Result<Vec<Datapoint>> {
+		self.range(pair, None, amount, at)
+	}
+
+	fn get_source_datapoints(
+		&self,
+		pair: &Pair,
+		source: &str,
+		amount: usize,
+		at: Option<i64>,
+	) -> Result<Vec<Datapoint>> {
+		self.range(pair, Some(source), amount, at)
+	}
+
+	// lists of different lengths mean a push only reached one of them, which shifts every entry after
+	// it. lost timestamps leave a gap in the spacing behind, so the lists still line up before the
+	// first gap and everything from there on is moved into the drifted lists, where heal_prices
+	// sees it as missing. lost prices leave no trace, so only the extra timestamps can be dropped
+	#[instrument(err, skip(self))]
+	fn repair(&self, pair: &Pair) -> Result<()> {
+		let mut connection = self.client.get_connection()?;
+		debug!("redis connection established");
+
+		let (prices_key, timestamps_key) = self.keys(pair, None);
+		let (drifted_prices_key, drifted_timestamps_key) = self.keys(pair, Some(DRIFTED_SOURCE));
+		let interval_secs = COLLECTION_INTERVAL.std_duration().as_secs() as i64;
+
+		let drifted: Option<(usize, usize, usize)> = redis::transaction(
+			&mut connection,
+			&[&prices_key, &timestamps_key],
+			|connection, pipe| {
+				let prices_len: usize = connection.llen(&prices_key)?;
+				let timestamps: Vec<i64> = connection.lrange(&timestamps_key, 0, -1)?;
+
+				if prices_len == timestamps.len() {
+					debug!(count = prices_len, "prices and timestamps are consistent");
+					return Ok(Some(None));
+				}
+
+				let aligned = match prices_len > timestamps.len() {
+					true => first_gap(&timestamps, interval_secs).unwrap_or(timestamps.len()),
+					false => {
+						error!(
+							prices = prices_len,
+							timestamps = timestamps.len(),
+							"prices were lost at an unknown position, the series might stay misaligned"
+						);
+						prices_len
+					}
+				};
+
+				let drifted_prices: Vec<String> = connection.lrange(&prices_key, aligned as isize, -1)?;
+				let drifted_timestamps = &timestamps[aligned..];
+
+				// ltrim with a stop of -1 keeps the whole list, so emptied lists have to be deleted
+				if aligned == 0 {
+					pipe.del(&prices_key).ignore().del(&timestamps_key).ignore();
+				} else {
+					pipe
+						.ltrim(&prices_key, 0, aligned as isize - 1)
+						.ignore()
+						.ltrim(&timestamps_key, 0, aligned as isize - 1)
+						.ignore();
+				}
+
+				if !drifted_prices.is_empty() {
+					pipe.rpush(&drifted_prices_key, &drifted_prices).ignore();
+				}
+
+				if !drifted_timestamps.is_empty() {
+					pipe
+						.rpush(&drifted_timestamps_key, drifted_timestamps)
+						.ignore();
+				}
+
+				Ok(
+					pipe
+						.query::<Option<()>>(connection)?
+						.map(|_| Some((aligned, drifted_prices.len(), drifted_timestamps.len()))),
+				)
+			},
+		)?;
+
+		if let Some((aligned, prices, timestamps)) = drifted {
+			warn!(
+				aligned,
+				prices,
+				timestamps,
+				"prices and timestamps were out of alignment, moved the drifted range aside"
+			);
+		}
+
+		Ok(())
+	}
+
+	#[instrument(err, skip(self, trades))]
+	fn store_trades(&self, pair: &Pair, trades: Vec<Trade>) -> Result<()> {
+		if trades.is_empty() {
+			return Ok(());
+		}
+
+		let mut connection = self.client.get_connection()?;
+
+		let members = trades
+			.iter()
+			.map(|trade| {
+				Ok((
+					Self::trade_id(trade.block_number, trade.log_index),
+					trade.timestamp,
+					serde_json::to_string(trade)?,
+				))
+			})
+			.collect::<Result<Vec<(String, i64, String)>>>()?;
+
+		// only trades whose id is new go into the sorted set, the id and the trade are written
+		// together, watching the ids so a concurrent write of the same trade makes this start over
+		let (ids_key, trades_key) = (self.trade_ids_key(pair), self.trades_key(pair));
+		let added: usize = redis::transaction(&mut connection, &[&ids_key], |connection, pipe| {
+			let stored: Vec<Option<String>> = redis::cmd("HMGET")
+				.arg(&ids_key)
+				.arg(
+					members
+						.iter()
+						.map(|(id, _, _)| id)
+						.collect::<Vec<&String>>(),
+				)
+				.query(connection)?;
+
+			let mut seen = HashSet::new();
+			let added: Vec<&(String, i64, String)> = members
+				.iter()
+				.zip(stored)
+				.filter(|((id, _, _), stored)| stored.is_none() && seen.insert(id))
+				.map(|(member, _)| member)
+				.collect();
+
+			if added.is_empty() {
+				return Ok(Some(0));
+			}
+
+			for (id, timestamp, member) in added.iter() {
+				pipe
+					.hset(&ids_key, id, member)
+					.ignore()
+					.zadd(&trades_key, member, *timestamp)
+					.ignore();
+			}
+
+			Ok(pipe.query::<Option<()>>(connection)?.map(|_| added.len()))
+		})?;
+
+		debug!(
+			count = added,
+			duplicates = trades.len() - added,
+			"stored trades"
+		);
+		Ok(())
+	}
+
+	fn get_trades(&self, pair: &Pair, from: i64, to: i64) -> Result<Vec<Trade>> {
+		let mut connection = self.client.get_connection()?;
+
+		let members: Vec<String> =
+			connection.zrangebyscore(self.trades_key(pair), from, format!("({}", to))?;
+
+		let mut trades = members
+			.iter()
+			.map(|member| serde_json::from_str(member).wrap_err("stored trade is not valid json"))
+			.collect::<Result<Vec<Trade>>>()?;
+
+		// trades of the same second are ordered by their json, not by their position in the chain
+		trades.sort_by_key(|trade| (trade.block_number, trade.log_index));
+		Ok(trades)
+	}
+
+	fn remove_trade(&self, pair: &Pair, block_number: u64, log_index: u64) -> Result<bool> {
+		let mut connection = self.client.get_connection()?;
+		let id = Self::trade_id(block_number, log_index);
+
+		let member: Option<String> = connection.hget(self.trade_ids_key(pair), &id)?;
+		let Some(member) = member else {
+			return Ok(false);
+		};
+
+		redis::pipe()
+			.atomic()
+			.zrem(self.trades_key(pair), member)
+			.ignore()
+			.hdel(self.trade_ids_key(pair), id)
+			.ignore()
+			.query::<()>(&mut connection)?;
+
+		Ok(true)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::first_gap;
+
+	#[test]
+	fn first_gap_in_the_spacing() {
+		let interval_secs = 300;
+
+		assert_eq!(first_gap(&[0, 300, 600, 900], interval_secs), None);
+		// collected a little late
+		assert_eq!(first_gap(&[0, 300, 660, 900], interval_secs), None);
+		assert_eq!(
+			first_gap(&[0, 300, 900, 1200, 1800], interval_secs),
+			Some(2)
+		);
+		assert_eq!(first_gap(&[0, 300, 300, 600], interval_secs), Some(2));
+		assert_eq!(first_gap(&[0, 600, 300], interval_secs), Some(1));
+	}
+}
@@ -0,0 +1,618 @@
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use ethers::types::Chain;
+use eyre::{eyre, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use shared::coin::Pair;
+use tracing::{debug, info, instrument};
+
+use super::{round_timestamp, PriceStore};
+use crate::datapoint::{BlockInfo, Datapoint, Provenance, SourceCandle, TimeType, Trade, Volume};
+
+// source of the collected series, single source prices use their own name
+const COLLECTED_SOURCE: &str = "";
+
+// columns added after the table was first created, added to older databases on open
+const ADDED_COLUMNS: [(&str, &str); 7] = [
+	("block_number", "INTEGER"),
+	("block_hash", "TEXT"),
+	("block_timestamp", "INTEGER"),
+	("provenance", "TEXT"),
+	("candle", "TEXT"),
+	("volume", "TEXT"),
+	("fees", "TEXT"),
+];
+
+/// durable store keyed by (chain, pair, source, timestamp), duplicate timestamps are ignored
+pub struct SqliteStore {
+	connection: Mutex<Connection>,
+	chain: u64,
+}
+
+impl SqliteStore {
+	pub fn open<T: AsRef<Path>>(path: T, chain: Chain) -> Result<Self> {
+		let connection = Connection::open(path)?;
+		// every chain has its own connection to the same database
+		connection.busy_timeout(Duration::from_secs(5))?;
+
+		Self::with_connection(connection, chain)
+	}
+
+	// creates the tables, or adds the missing columns to older ones
+	fn with_connection(connection: Connection, chain: Chain) -> Result<Self> {
+		connection.execute_batch(
+			"CREATE TABLE IF NOT EXISTS prices (
+				chain INTEGER NOT NULL,
+				pair TEXT NOT NULL,
+				source TEXT NOT NULL,
+				timestamp INTEGER NOT NULL,
+				price REAL NOT NULL,
+				block_number INTEGER,
+				block_hash TEXT,
+				block_timestamp INTEGER,
+				provenance TEXT,
+				-- fallback candle of backfilled prices, as json
+				candle TEXT,
+				-- on-chain volume of collected prices, as json
+				volume TEXT,
+				-- fee tiers of quoted prices, as json
+				fees TEXT,
+				UNIQUE (chain, pair, source, timestamp)
+			);
+			CREATE TABLE IF NOT EXISTS trades (
+				chain INTEGER NOT NULL,
+				pair TEXT NOT NULL,
+				timestamp INTEGER NOT NULL,
+				block_number INTEGER NOT NULL,
+				transaction_hash TEXT NOT NULL,
+				log_index INTEGER NOT NULL,
+				price REAL NOT NULL,
+				base_amount REAL NOT NULL,
+				quote_amount REAL NOT NULL,
+				tick INTEGER NOT NULL,
+				UNIQUE (chain, pair, block_number, log_index)
+			);",
+		)?;
+
+		let columns = connection
+			.prepare("SELECT name FROM pragma_table_info('prices')")?
+			.query_map([], |row| row.get(0))?
+			.collect::<rusqlite::Result<Vec<String>>>()?;
+
+		for (column, column_type) in ADDED_COLUMNS {
+			if !columns.iter().any(|name| name == column) {
+				info!(column, "adding missing column");
+				connection.execute_batch(&format!(
+					"ALTER TABLE prices ADD COLUMN {} {};",
+					column, column_type
+				))?;
+			}
+		}
+
+		Ok(Self {
+			connection: Mutex::new(connection),
+			chain: chain.into(),
+		})
+	}
+
+	fn connection(&self) -> Result<MutexGuard<'_, Connection>> {
+		self
+			.connection
+			.lock()
+			.map_err(|_| eyre!("sqlite connection lock was poisoned"))
+	}
+
+	// datapoints at an already stored timestamp are ignored, when appending so are the ones at or
+	// before the newest stored timestamp
+	fn insert(
+		&self,
+		pair: &Pair,
+		source: &str,
+		datapoints: Vec<Datapoint>,
+		append: bool,
+	) -> Result<usize> {
+		let mut connection = self.connection()?;
+		let transaction = connection.transaction()?;
+
+		let mut last: Option<i64> = match append {
+			true => transaction.query_row(
+				"SELECT MAX(timestamp) FROM prices WHERE chain = ?1 AND pair = ?2 AND source = ?3",
+				params![self.chain, pair.to_string(), source],
+				|row| row.get(0),
+			)?,
+			false => None,
+		};
+
+		let mut count = 0;
+		{
+			let mut statement = transaction.prepare_cached(
+				"INSERT OR IGNORE INTO prices
+				(chain, pair, source, timestamp, price, block_number, block_hash, block_timestamp, provenance, candle, volume, fees)
+				VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+			)?;
+
+			for datapoint in datapoints.iter() {
+				let timestamp = round_timestamp(datapoint.timestamp)?;
+				if last.is_some_and(|last| last >= timestamp) {
+					continue;
+				}
+
+				if append {
+					last = Some(timestamp);
+				}
+
+				count += statement.execute(params![
+					self.chain,
+					pair.to_string(),
+					source,
+					timestamp,
+					datapoint.price,
+					datapoint.block.map(|block| block.number),
+					datapoint.block.map(|block| format!("{:?}", block.hash)),
+					datapoint.block.map(|block| block.timestamp),
+					datapoint.provenance.as_str(),
+					datapoint
+						.candle
+						.map(|candle| serde_json::to_string(&candle))
+						.transpose()?,
+					datapoint
+						.volume
+						.map(|volume| serde_json::to_string(&volume))
+						.transpose()?,
+					datapoint
+						.fees
+						.as_ref()
+						.map(serde_json::to_string)
+						.transpose()?
+				])?;
+			}
+		}
+
+		transaction.commit()?;
+
+		if count < datapoints.len() {
+			debug!(
+				skipped = datapoints.len() - count,
+				"skipped already stored datapoints"
+			);
+		}
+
+		info!(count = count, "stored datapoints");
+		Ok(count)
+	}
+
+	fn select(
+		&self,
+		pair: &Pair,
+		source: &str,
+		amount: usize,
+		at: Option<i64>,
+	) -> Result<Vec<Datapoint>> {
+		let connection = self.connection()?;
+		let mut statement = connection.prepare_cached(
+			"SELECT price, timestamp, block_number, block_hash, block_timestamp, provenance, candle, volume, fees FROM prices
+			WHERE chain = ?1 AND pair = ?2 AND source = ?3 AND timestamp <= ?4
+			ORDER BY timestamp DESC LIMIT ?5",
+		)?;
+
+		let mut datapoints = statement
+			.query_map(
+				params![
+					self.chain,
+					pair.to_string(),
+					source,
+					at.unwrap_or(i64::MAX),
+					amount as i64
+				],
+				|row| {
+					Ok((
+						row.get::<_, f64>(0)?,
+						row.get::<_, i64>(1)?,
+						row.get::<_, Option<u64>>(2)?,
+						row.get::<_, Option<String>>(3)?,
+						row.get::<_, Option<i64>>(4)?,
+						row.get::<_, Option<String>>(5)?,
+						row.get::<_, Option<String>>(6)?,
+						row.get::<_, Option<String>>(7)?,
+						row.get::<_, Option<String>>(8)?,
+					))
+				},
+			)?
+			.map(|row| {
+				let (
+					price,
+					timestamp,
+					block_number,
+					block_hash,
+					block_timestamp,
+					provenance,
+					candle,
+					volume,
+					fees,
+				) = row?;
+				let block = match (block_number, block_hash, block_timestamp) {
+					(Some(number), Some(hash), Some(timestamp)) => Some(BlockInfo {
+						number,
+						hash: hash.parse()?,
+						timestamp,
+					}),
+					_ => None,
+				};
+
+				let provenance = match provenance {
+					Some(provenance) => provenance.parse()?,
+					None => Provenance::default(),
+				};
+
+				let candle = candle
+					.map(|candle| serde_json::from_str::<SourceCandle>(&candle))
+					.transpose()?;
+				let volume = volume
+					.map(|volume| serde_json::from_str::<Volume>(&volume))
+					.transpose()?;
+				let fees = fees
+					.map(|fees| serde_json::from_str::<Vec<u32>>(&fees))
+					.transpose()?;
+
+				Ok(
+					Datapoint::new(price, TimeType::Timestamp(timestamp))?
+						.with_block(block)
+						.with_candle(candle)
+						.with_volume(volume)
+						.with_fees(fees)
+						.with_provenance(provenance),
+				)
+			})
+			.collect::<Result<Vec<Datapoint>>>()?;
+
+		datapoints.reverse();
+		Ok(datapoints)
+	}
+}
+
+impl PriceStore for SqliteStore {
+	#[instrument(err, skip(self, datapoints))]
+	fn store_prices(&self, pair: &Pair, datapoints: Vec<Datapoint>) -> Result<usize> {
+		self.insert(pair, COLLECTED_SOURCE, datapoints, true)
+	}
+
+	#[instrument(err, skip(self, datapoints))]
+	fn insert_prices(&self, pair: &Pair, datapoints: Vec<Datapoint>) -> Result<()> {
+		// rows are ordered by timestamp when read, so inserting only has to skip stored timestamps
+		self.insert(pair, COLLECTED_SOURCE, datapoints, false)?;
+		Ok(())
+	}
+
+	#[instrument(err, skip(self, datapoints))]
+	fn store_source_prices(
+		&self,
+		pair: &Pair,
+		source: &str,
+		datapoints: Vec<Datapoint>,
+	) -> Result<usize> {
+		self.insert(pair, source, datapoints, true)
+	}
+
+	fn last_timestamp(&self, pair: &Pair) -> Result<Option<i64>> {
+		Ok(
+			self
+				.connection()?
+				.query_row(
+					"SELECT MAX(timestamp) FROM prices WHERE chain = ?1 AND pair = ?2 AND source = ?3",
+					params![self.chain, pair.to_string(), COLLECTED_SOURCE],
+					|row| row.get(0),
+				)
+				.optional()?
+				.flatten(),
+		)
+	}
+
+	fn get_timestamps(&self, pair: &Pair) -> Result<Vec<i64>> {
+		let connection = self.connection()?;
+		let mut statement = connection.prepare_cached(
+			"SELECT timestamp FROM prices WHERE chain = ?1 AND pair = ?2 AND source = ?3
+			ORDER BY timestamp",
+		)?;
+
+		let timestamps = statement
+			.query_map(
+				params![self.chain, pair.to_string(), COLLECTED_SOURCE],
+				|row| row.get(0),
+			)?
+			.collect::<rusqlite::Result<Vec<i64>>>()?;
+
+		Ok(timestamps)
+	}
+
+	fn get_datapoints(&self, pair: &Pair, amount: usize, at: Option<i64>) -> Result<Vec<Datapoint>> {
+		self.select(pair, COLLECTED_SOURCE, amount, at)
+	}
+
+	fn get_source_datapoints(
+		&self,
+		pair: &Pair,
+		source: &str,
+		amount: usize,
+		at: Option<i64>,
+	) -> Result<Vec<Datapoint>> {
+		self.select(pair, source, amount, at)
+	}
+
+	fn repair(&self, _pair: &Pair) -> Result<()> {
+		// rows hold both the price and timestamp and are unique per timestamp, so there is nothing to repair
+		Ok(())
+	}
+
+	#[instrument(err, skip(self, trades))]
+	fn store_trades(&self, pair: &Pair, trades: Vec<Trade>) -> Result<()> {
+		let mut connection = self.connection()?;
+		let transaction = connection.transaction()?;
+
+		let mut count = 0;
+		{
+			let mut statement = transaction.prepare_cached(
+				"INSERT OR IGNORE INTO trades
+				(chain, pair, timestamp, block_number, transaction_hash, log_index, price, base_amount, quote_amount, tick)
+				VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+			)?;
+
+			for trade in trades.iter() {
+				count += statement.execute(params![
+					self.chain,
+					pair.to_string(),
+					trade.timestamp,
+					trade.block_number,
+					format!("{:?}", trade.transaction_hash),
+					trade.log_index,
+					trade.price,
+					trade.base_amount,
+					trade.quote_amount,
+					trade.tick
+				])?;
+			}
+		}
+
+		transaction.commit()?;
+
+		debug!(count, duplicates = trades.len() - count, "stored trades");
+		Ok(())
+	}
+
+	fn get_trades(&self, pair: &Pair, from: i64, to: i64) -> Result<Vec<Trade>> {
+		let connection = self.connection()?;
+		let mut statement = connection.prepare_cached(
+			"SELECT timestamp, block_number, transaction_hash, log_index, price, base_amount, quote_amount, tick
+			FROM trades WHERE chain = ?1 AND pair = ?2 AND timestamp >= ?3 AND timestamp < ?4
+			ORDER BY block_number, log_index",
+		)?;
+
+		let trades = statement
+			.query_map(params![self.chain, pair.to_string(), from, to], |row| {
+				Ok((
+					row.get::<_, i64>(0)?,
+					row.get::<_, u64>(1)?,
+					row.get::<_, String>(2)?,
+					row.get::<_, u64>(3)?,
+					row.get::<_, f64>(4)?,
+					row.get::<_, f64>(5)?,
+					row.get::<_, f64>(6)?,
+					row.get::<_, i32>(7)?,
+				))
+			})?
+			.map(|row| {
+				let (
+					timestamp,
+					block_number,
+					transaction_hash,
+					log_index,
+					price,
+					base_amount,
+					quote_amount,
+					tick,
+				) = row?;
+
+				Ok(Trade {
+					timestamp,
+					block_number,
+					transaction_hash: transaction_hash.parse()?,
+					log_index,
+					price,
+					base_amount,
+					quote_amount,
+					tick,
+				})
+			})
+			.collect::<Result<Vec<Trade>>>()?;
+
+		Ok(trades)
+	}
+
+	fn remove_trade(&self, pair: &Pair, block_number: u64, log_index: u64) -> Result<bool> {
+		let removed = self.connection()?.execute(
+			"DELETE FROM trades WHERE chain = ?1 AND pair = ?2 AND block_number = ?3 AND log_index = ?4",
+			params![self.chain, pair.to_string(), block_number, log_index],
+		)?;
+
+		Ok(removed > 0)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ethers::types::{Chain, H256};
+	use rusqlite::Connection;
+
+	use super::SqliteStore;
+	use crate::datapoint::{BlockInfo, Datapoint, Provenance, TimeType, Trade, Volume};
+	use crate::store::PriceStore;
+	use crate::testing::{datapoint, pair, prices, START};
+
+	fn store() -> SqliteStore {
+		SqliteStore::with_connection(Connection::open_in_memory().unwrap(), Chain::Mainnet).unwrap()
+	}
+
+	fn trade(block_number: u64, log_index: u64, minutes: i64) -> Trade {
+		Trade {
+			timestamp: START + minutes * 60,
+			block_number,
+			transaction_hash: H256::repeat_byte(1),
+			log_index,
+			price: 2000.0,
+			base_amount: 1.0,
+			quote_amount: -2000.0,
+			tick: 0,
+		}
+	}
+
+	#[test]
+	fn datapoints_are_read_back_as_stored() {
+		let (store, pair) = (store(), pair());
+		let block = BlockInfo {
+			number: 19_000_000,
+			hash: H256::repeat_byte(2),
+			timestamp: START - 3,
+		};
+		let stored = Datapoint::new(2000.0, TimeType::Timestamp(START + 3))
+			.unwrap()
+			.with_block(Some(block))
+			.with_provenance(Provenance::OnChain)
+			.with_volume(Some(Volume::new(18_999_976, 19_000_000)))
+			.with_fees(Some(vec![500]));
+
+		assert_eq!(store.store_prices(&pair, vec![stored]).unwrap(), 1);
+
+		let datapoints = store.get_datapoints(&pair, 10, None).unwrap();
+		assert_eq!(prices(&datapoints), [(0, 2000.0)]);
+
+		let datapoint = &datapoints[0];
+		assert_eq!(datapoint.provenance, Provenance::OnChain);
+		assert_eq!(
+			datapoint.block.map(|block| (block.number, block.hash)),
+			Some((block.number, block.hash))
+		);
+		assert_eq!(
+			datapoint
+				.volume
+				.map(|volume| (volume.from_block, volume.to_block)),
+			Some((18_999_976, 19_000_000))
+		);
+		assert_eq!(datapoint.fees, Some(vec![500]));
+	}
+
+	#[test]
+	fn stored_prices_only_append() {
+		let (store, pair) = (store(), pair());
+		assert_eq!(
+			store
+				.store_prices(&pair, vec![datapoint(0, 1.0), datapoint(10, 3.0)])
+				.unwrap(),
+			2
+		);
+
+		// already stored and older timestamps are left to insert_prices
+		assert_eq!(
+			store
+				.store_prices(
+					&pair,
+					vec![datapoint(5, 2.0), datapoint(10, 30.0), datapoint(15, 4.0)]
+				)
+				.unwrap(),
+			1
+		);
+		assert_eq!(
+			store
+				.store_prices(&pair, vec![datapoint(15, 40.0)])
+				.unwrap(),
+			0
+		);
+
+		store
+			.insert_prices(&pair, vec![datapoint(5, 2.0), datapoint(15, 40.0)])
+			.unwrap();
+		assert_eq!(
+			prices(&store.get_datapoints(&pair, 10, None).unwrap()),
+			[(0, 1.0), (5, 2.0), (10, 3.0), (15, 4.0)]
+		);
+		assert_eq!(store.last_timestamp(&pair).unwrap(), Some(START + 15 * 60));
+	}
+
+	#[test]
+	fn source_prices_and_chains_are_kept_apart() {
+		let connection = Connection::open_in_memory().unwrap();
+		let (store, pair) = (
+			SqliteStore::with_connection(connection, Chain::Mainnet).unwrap(),
+			pair(),
+		);
+		store.store_prices(&pair, vec![datapoint(0, 1.0)]).unwrap();
+		store
+			.store_source_prices(&pair, "chainlink", vec![datapoint(0, 1.1)])
+			.unwrap();
+
+		assert_eq!(
+			prices(&store.get_datapoints(&pair, 10, None).unwrap()),
+			[(0, 1.0)]
+		);
+		assert_eq!(
+			prices(
+				&store
+					.get_source_datapoints(&pair, "chainlink", 10, None)
+					.unwrap()
+			),
+			[(0, 1.1)]
+		);
+		assert_eq!(store.get_timestamps(&pair).unwrap(), [START]);
+	}
+
+	#[test]
+	fn trades_are_stored_once_and_removed() {
+		let (store, pair) = (store(), pair());
+		store
+			.store_trades(&pair, vec![trade(1, 0, 0), trade(1, 1, 0), trade(2, 0, 5)])
+			.unwrap();
+		store.store_trades(&pair, vec![trade(1, 1, 0)]).unwrap();
+
+		let stored = |from: i64, to: i64| {
+			store
+				.get_trades(&pair, START + from * 60, START + to * 60)
+				.unwrap()
+				.iter()
+				.map(|trade| (trade.block_number, trade.log_index))
+				.collect::<Vec<(u64, u64)>>()
+		};
+		assert_eq!(stored(0, 10), [(1, 0), (1, 1), (2, 0)]);
+		assert_eq!(stored(0, 5), [(1, 0), (1, 1)]);
+
+		assert!(store.remove_trade(&pair, 1, 1).unwrap());
+		assert!(!store.remove_trade(&pair, 1, 1).unwrap());
+		assert_eq!(stored(0, 10), [(1, 0), (2, 0)]);
+	}
+
+	#[test]
+	fn older_databases_get_the_added_columns() {
+		let connection = Connection::open_in_memory().unwrap();
+		connection
+			.execute_batch(
+				"CREATE TABLE prices (
+					chain INTEGER NOT NULL,
+					pair TEXT NOT NULL,
+					source TEXT NOT NULL,
+					timestamp INTEGER NOT NULL,
+					price REAL NOT NULL,
+					UNIQUE (chain, pair, source, timestamp)
+				);
+				INSERT INTO prices VALUES (1, 'usdc-weth', '', 1704067200, 1.0);",
+			)
+			.unwrap();
+
+		let store = SqliteStore::with_connection(connection, Chain::Mainnet).unwrap();
+		let pair = pair();
+		store.store_prices(&pair, vec![datapoint(5, 2.0)]).unwrap();
+
+		let datapoints = store.get_datapoints(&pair, 10, None).unwrap();
+		assert_eq!(prices(&datapoints), [(0, 1.0), (5, 2.0)]);
+		assert_eq!(datapoints[0].provenance, Provenance::Unknown);
+		assert!(datapoints[0].block.is_none());
+	}
+}
@@ -0,0 +1,477 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use ethers::contract::parse_log;
+use ethers::prelude::*;
+use eyre::{eyre, OptionExt, Result};
+use futures::{future, StreamExt};
+use shared::coin::Pair;
+use tracing::{debug, error, info, warn};
+
+use crate::config::{ChainConfig, PairConfig};
+use crate::datapoint::{Trade, Volume};
+use crate::price::{FeeSelection, Route};
+use crate::store::PriceStore;
+
+// wait before a dropped subscription is set up again
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(5);
+
+abigen!(
+	UniswapV3Pool,
+	r#"[
+		event Swap(address indexed sender, address indexed recipient, int256 amount0, int256 amount1, uint160 sqrtPriceX96, uint128 liquidity, int24 tick)
+	]"#
+);
+
+abigen!(
+	UniswapV3Factory,
+	r#"[
+		function getPool(address tokenA, address tokenB, uint24 fee) external view returns (address pool)
+	]"#
+);
+
+// the limbs are added up from the most significant one, rounding only at the end of the mantissa
+fn u256_to_f64(value: U256) -> f64 {
+	value
+		.0
+		.iter()
+		.rev()
+		.fold(0.0, |result, limb| result * 2_f64.powi(64) + *limb as f64)
+}
+
+fn i256_to_f64(value: I256) -> f64 {
+	let magnitude = u256_to_f64(value.unsigned_abs());
+	match value.is_negative() {
+		true => -magnitude,
+		false => magnitude,
+	}
+}
+
+/// uniswap v3 pool the swaps of a pair are read from
+#[derive(Clone, Debug)]
+pub struct Pool {
+	pub pair: Pair,
+	pub address: Address,
+}
+
+impl Pool {
+	// pools sort their two tokens by address
+	fn base_is_token0(&self) -> bool {
+		self.pair.1.address < self.pair.0.address
+	}
+
+	/// amounts of `pair.1` and `pair.0` a swap in this pool took out of it, negative if put in
+	pub fn amounts(&self, swap: &SwapFilter) -> (f64, f64) {
+		let (quote, base) = (&self.pair.0, &self.pair.1);
+		let (base_delta, quote_delta) = match self.base_is_token0() {
+			true => (swap.amount_0, swap.amount_1),
+			false => (swap.amount_1, swap.amount_0),
+		};
+
+		// the amounts of a swap are what the pool received
+		(
+			-i256_to_f64(base_delta) / 10_f64.powi(base.decimals as i32),
+			-i256_to_f64(quote_delta) / 10_f64.powi(quote.decimals as i32),
+		)
+	}
+
+	/// turns a swap in this pool into a trade of the pair
+	pub fn trade(&self, swap: &SwapFilter, log: &Log, timestamp: i64) -> Result<Trade> {
+		let (quote, base) = (&self.pair.0, &self.pair.1);
+		let base_is_token0 = self.base_is_token0();
+		let (decimals0, decimals1) = match base_is_token0 {
+			true => (base.decimals, quote.decimals),
+			false => (quote.decimals, base.decimals),
+		};
+		let (base_amount, quote_amount) = self.amounts(swap);
+
+		// sqrtPriceX96 is the square root of token1 per token0 in their smallest units, as Q64.96
+		let raw_price = (u256_to_f64(swap.sqrt_price_x96) / 2_f64.powi(96)).powi(2);
+		let token0_price = raw_price * 10_f64.powi(decimals0 as i32 - decimals1 as i32);
+
+		Ok(Trade {
+			timestamp,
+			block_number: log
+				.block_number
+				.ok_or_eyre("swap log has no block number")?
+				.as_u64(),
+			transaction_hash: log
+				.transaction_hash
+				.ok_or_eyre("swap log has no transaction hash")?,
+			log_index: log
+				.log_index
+				.ok_or_eyre("swap log has no log index")?
+				.as_u64(),
+			price: match base_is_token0 {
+				true => token0_price,
+				false => 1.0 / token0_price,
+			},
+			base_amount,
+			quote_amount,
+			tick: swap.tick,
+		})
+	}
+}
+
+async fn resolve_pool<M: Middleware + 'static>(
+	factory: &UniswapV3Factory<M>,
+	config: &PairConfig,
+) -> Result<Pool> {
+	let address = match (config.pool, &config.route) {
+		(Some(pool), _) => pool,
+		(None, Route::Single(FeeSelection::Tier(fee))) => {
+			factory
+				.get_pool(config.pair.0.address, config.pair.1.address, *fee as u32)
+				.call()
+				.await?
+		}
+		_ => {
+			return Err(eyre!(
+				"pair is not quoted through a single fee tier, its pool has to be configured"
+			))
+		}
+	};
+
+	if address.is_zero() {
+		return Err(eyre!("factory has no pool for the pair"));
+	}
+
+	Ok(Pool {
+		pair: config.pair.clone(),
+		address,
+	})
+}
+
+/// the pool of every pair on `chain` that has one, pools that are not configured are looked up
+/// from the factory
+pub async fn resolve_pools<M: Middleware + 'static>(
+	client: Arc<M>,
+	chain: &ChainConfig,
+) -> Vec<Pool> {
+	let factory = UniswapV3Factory::new(chain.factory, client);
+
+	future::join_all(
+		chain
+			.pairs
+			.iter()
+			.map(|config| resolve_pool(&factory, config)),
+	)
+	.await
+	.into_iter()
+	.zip(chain.pairs.iter())
+	.filter_map(|(pool, config)| match pool {
+		Ok(pool) => {
+			debug!(pair = ?pool.pair, pool = ?pool.address, "resolved pool");
+			Some(pool)
+		}
+		Err(error) => {
+			warn!(error = ?error, pair = ?config.pair, "no pool to read swaps from");
+			None
+		}
+	})
+	.collect()
+}
+
+// swap logs of `pools`
+fn swap_filter(pools: &[Pool]) -> Filter {
+	Filter::new()
+		.address(
+			pools
+				.iter()
+				.map(|pool| pool.address)
+				.collect::<Vec<Address>>(),
+		)
+		.event(&SwapFilter::abi_signature())
+}
+
+/// adds up the swaps in the pools of a chain from one collection to the next
+pub struct VolumeTracker {
+	pools: Vec<Pool>,
+	// blocks mined during a collection interval, the first read and the one after downtime only
+	// go back this far
+	interval_blocks: u64,
+	// last block whose swaps went into a stored datapoint, by pair
+	last_blocks: Mutex<HashMap<String, u64>>,
+}
+
+impl VolumeTracker {
+	pub fn new(pools: Vec<Pool>, interval_blocks: u64) -> Self {
+		Self {
+			pools,
+			interval_blocks: interval_blocks.max(1),
+			last_blocks: Mutex::new(HashMap::new()),
+		}
+	}
+
+	fn last_blocks(&self) -> MutexGuard<'_, HashMap<String, u64>> {
+		self
+			.last_blocks
+			.lock()
+			.unwrap_or_else(PoisonError::into_inner)
+	}
+
+	/// volume of every pool in the blocks after the ones committed for its pair up to `to_block`,
+	/// keyed by pair. blocks are only counted once their volume is committed, so the volume of a
+	/// datapoint that could not be stored goes into the next one
+	pub async fn volumes<M: Middleware + 'static>(
+		&self,
+		client: &M,
+		to_block: u64,
+	) -> Result<HashMap<String, Volume>> {
+		let mut volumes: HashMap<String, Volume> = {
+			let last_blocks = self.last_blocks();
+
+			self
+				.pools
+				.iter()
+				.map(|pool| {
+					let pair = pool.pair.to_string();
+					let from_block = match last_blocks.get(&pair) {
+						Some(last_block)
+							if to_block.saturating_sub(*last_block) <= 2 * self.interval_blocks =>
+						{
+							last_block + 1
+						}
+						_ => (to_block + 1).saturating_sub(self.interval_blocks),
+					};
+
+					(pair, Volume::new(from_block, to_block))
+				})
+				// nothing was mined since the last collection
+				.filter(|(_, volume)| volume.from_block <= to_block)
+				.collect()
+		};
+
+		let Some(from_block) = volumes.values().map(|volume| volume.from_block).min() else {
+			return Ok(volumes);
+		};
+
+		let logs = client
+			.get_logs(
+				&swap_filter(&self.pools)
+					.from_block(from_block)
+					.to_block(to_block),
+			)
+			.await?;
+
+		for log in logs {
+			if log.removed == Some(true) {
+				continue;
+			}
+
+			let Some(pool) = self.pools.iter().find(|pool| pool.address == log.address) else {
+				continue;
+			};
+
+			// already counted for this pair
+			let Some(volume) = volumes.get_mut(&pool.pair.to_string()).filter(|volume| {
+				log
+					.block_number
+					.is_some_and(|block| block.as_u64() >= volume.from_block)
+			}) else {
+				continue;
+			};
+
+			match parse_log::<SwapFilter>(log) {
+				Ok(swap) => {
+					let (base_amount, quote_amount) = pool.amounts(&swap);
+					volume.add_swap(base_amount, quote_amount);
+				}
+				Err(error) => error!(error = ?error, pool = ?pool.address, "error decoding swap"),
+			}
+		}
+
+		debug!(
+			from_block,
+			to_block,
+			pools = self.pools.len(),
+			"read swap volumes"
+		);
+		Ok(volumes)
+	}
+
+	/// marks the blocks of `volume` as counted for `pair`, once the datapoint carrying it is stored
+	pub fn commit(&self, pair: &Pair, volume: &Volume) {
+		self.last_blocks().insert(pair.to_string(), volume.to_block);
+	}
+}
+
+async fn subscribe_swaps(ws_url: &str, chain: &ChainConfig, store: &dyn PriceStore) -> Result<()> {
+	let provider = Arc::new(Provider::<Ws>::connect(ws_url).await?);
+
+	let pools = resolve_pools(provider.clone(), chain).await;
+	if pools.is_empty() {
+		return Err(eyre!("no pools to subscribe to"));
+	}
+
+	let mut logs = provider.subscribe_logs(&swap_filter(&pools)).await?;
+	info!(chain = %chain.chain, pools = pools.len(), "subscribed to swaps");
+
+	// logs arrive in block order, so only the timestamp of the latest block has to be kept
+	let mut latest_block: Option<(U64, i64)> = None;
+
+	while let Some(log) = logs.next().await {
+		let Some(pool) = pools.iter().find(|pool| pool.address == log.address) else {
+			continue;
+		};
+
+		// the swap never happened on the canonical chain
+		if log.removed == Some(true) {
+			let (Some(block_number), Some(log_index)) = (log.block_number, log.log_index) else {
+				continue;
+			};
+
+			match store.remove_trade(&pool.pair, block_number.as_u64(), log_index.as_u64()) {
+				Ok(removed) => warn!(
+					transaction = ?log.transaction_hash,
+					removed,
+					"swap was reorged out, removing its trade"
+				),
+				Err(error) => error!(error = ?error, pair = ?pool.pair, "error removing reorged trade"),
+			}
+
+			continue;
+		}
+
+		let swap = match parse_log::<SwapFilter>(log.clone()) {
+			Ok(swap) => swap,
+			Err(error) => {
+				error!(error = ?error, pool = ?pool.address, "error decoding swap");
+				continue;
+			}
+		};
+
+		let block_number = log
+			.block_number
+			.ok_or_eyre("swap log has no block number")?;
+		let timestamp = match latest_block {
+			Some((number, timestamp)) if number == block_number => timestamp,
+			_ => {
+				let timestamp = provider
+					.get_block(block_number)
+					.await?
+					.ok_or_eyre("block of swap not found")?
+					.timestamp
+					.as_u64() as i64;
+
+				latest_block = Some((block_number, timestamp));
+				timestamp
+			}
+		};
+
+		let result = pool.trade(&swap, &log, timestamp).and_then(|trade| {
+			debug!(pair = ?pool.pair, price = trade.price, amount = trade.base_amount, "swap");
+			store.store_trades(&pool.pair, vec![trade])
+		});
+
+		if let Err(error) = result {
+			error!(error = ?error, pair = ?pool.pair, "error storing trade");
+		}
+	}
+
+	Err(eyre!("swap subscription ended"))
+}
+
+/// stores every swap in the pools of `chain` as it happens, the subscription is set up again
+/// whenever it drops. swaps made while it was down are not recovered
+pub async fn stream_swaps(ws_url: String, chain: &'static ChainConfig, store: Arc<dyn PriceStore>) {
+	loop {
+		if let Err(error) = subscribe_swaps(&ws_url, chain, store.as_ref()).await {
+			error!(error = ?error, chain = %chain.chain, "swap subscription failed");
+		}
+
+		warn!(chain = %chain.chain, backoff = ?RECONNECT_BACKOFF, "resubscribing to swaps");
+		tokio::time::sleep(RECONNECT_BACKOFF).await;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use ethers::prelude::*;
+
+	use super::{Pool, SwapFilter};
+	use crate::testing::{self, START};
+
+	// usdc sorts before weth, so the real pool has weth as token1
+	fn usdc_weth_pool() -> Pool {
+		Pool {
+			pair: testing::pair(),
+			address: Address::zero(),
+		}
+	}
+
+	// the same pair with the token addresses swapped, so weth is token0
+	fn weth_first_pool() -> Pool {
+		let mut pool = usdc_weth_pool();
+		std::mem::swap(&mut pool.pair.0.address, &mut pool.pair.1.address);
+		pool
+	}
+
+	fn swap(amount_0: i128, amount_1: i128, sqrt_price_x96: &str) -> SwapFilter {
+		SwapFilter {
+			sender: Address::zero(),
+			recipient: Address::zero(),
+			amount_0: I256::from(amount_0),
+			amount_1: I256::from(amount_1),
+			sqrt_price_x96: U256::from_dec_str(sqrt_price_x96).unwrap(),
+			liquidity: 0,
+			tick: 0,
+		}
+	}
+
+	fn log() -> Log {
+		Log {
+			block_number: Some(U64::from(19_000_000)),
+			transaction_hash: Some(H256::repeat_byte(1)),
+			log_index: Some(U256::from(7)),
+			..Log::default()
+		}
+	}
+
+	fn assert_close(actual: f64, expected: f64) {
+		assert!(
+			(actual - expected).abs() <= expected.abs() * 1e-9,
+			"{} is not {}",
+			actual,
+			expected
+		);
+	}
+
+	#[test]
+	fn base_is_token1() {
+		let pool = usdc_weth_pool();
+		// 1 weth sold into the pool for 2000 usdc, at a price of 2000 usdc per weth
+		let swap = swap(
+			-2_000_000_000,
+			1_000_000_000_000_000_000,
+			"1771595571142957102961017161607260",
+		);
+
+		let (base_amount, quote_amount) = pool.amounts(&swap);
+		assert_close(base_amount, -1.0);
+		assert_close(quote_amount, 2000.0);
+
+		let trade = pool.trade(&swap, &log(), START).unwrap();
+		assert_close(trade.price, 2000.0);
+		assert_eq!((trade.block_number, trade.log_index), (19_000_000, 7));
+	}
+
+	#[test]
+	fn base_is_token0() {
+		let pool = weth_first_pool();
+		// 0.5 weth bought from the pool for 1000 usdc, at a price of 2000 usdc per weth
+		let swap = swap(
+			-500_000_000_000_000_000,
+			1_000_000_000,
+			"3543191142285914205922034",
+		);
+
+		let (base_amount, quote_amount) = pool.amounts(&swap);
+		assert_close(base_amount, 0.5);
+		assert_close(quote_amount, -1000.0);
+
+		let trade = pool.trade(&swap, &log(), START).unwrap();
+		assert_close(trade.price, 2000.0);
+	}
+}
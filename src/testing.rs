@@ -0,0 +1,45 @@
+use ethers::types::Chain;
+use shared::coin::Pair;
+
+use crate::datapoint::{Datapoint, KrakenDatapoint, TimeType};
+use crate::store::MemoryStore;
+
+/// 2024-01-01 00:00:00 utc, the series of every test start here
+pub const START: i64 = 1_704_067_200;
+
+/// pair the tests store prices of
+pub fn pair() -> Pair {
+	Pair::usdc_weth(Some(Chain::Mainnet as u64))
+}
+
+/// empty memory store and the pair to store prices of in it
+pub fn memory_store() -> (MemoryStore, Pair) {
+	(MemoryStore::new(), pair())
+}
+
+/// datapoint of `price`, `minutes` after the start
+pub fn datapoint(minutes: i64, price: f64) -> Datapoint {
+	Datapoint::new(price, TimeType::Timestamp(START + minutes * 60)).unwrap()
+}
+
+/// (minutes after the start, price) of every datapoint
+pub fn prices(datapoints: &[Datapoint]) -> Vec<(i64, f64)> {
+	datapoints
+		.iter()
+		.map(|datapoint| ((datapoint.timestamp - START) / 60, datapoint.price))
+		.collect()
+}
+
+/// candle of the hour `hours` after the start, opening at its close
+pub fn candle(hours: i64, close: f32, high: f32, low: f32) -> KrakenDatapoint {
+	KrakenDatapoint {
+		timestamp: START + hours * 60 * 60,
+		open: close,
+		high,
+		low,
+		close,
+		vwap: close,
+		volume: 1.0,
+		count: 1,
+	}
+}